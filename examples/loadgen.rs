@@ -0,0 +1,146 @@
+//! Load generator for trying out buffer configs and reproducing bug reports
+//!
+//! cargo run --example loadgen -- --items 1000 --rate 200 --burst-every 100 --burst-size 50 \
+//!     --fail-prob 0.2 --soft-cap 64 --hard-cap 256 --release-after-ms 500 --backoff-ms 100
+
+use async_io::Timer;
+use futures_lite::future;
+use relabuf::{ExponentialBackoff, Reason, RelaBuf, RelaBufConfig, RelaBufProducer};
+use std::{
+    collections::HashMap,
+    env, process,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+struct Args {
+    items: usize,
+    rate: f64,
+    burst_every: usize,
+    burst_size: usize,
+    fail_prob: f64,
+    soft_cap: usize,
+    hard_cap: usize,
+    release_after: Duration,
+    backoff: Option<Duration>,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: loadgen [--items N] [--rate PER_SEC] [--burst-every N] [--burst-size N] \
+         [--fail-prob 0..1] [--soft-cap N] [--hard-cap N] [--release-after-ms MS] [--backoff-ms MS]"
+    );
+    process::exit(2)
+}
+
+fn value<V: FromStr>(args: &mut impl Iterator<Item = String>) -> V {
+    args.next()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+fn parse_args() -> Args {
+    let mut parsed = Args {
+        items: 1000,
+        rate: 500.0,
+        burst_every: 0,
+        burst_size: 0,
+        fail_prob: 0.1,
+        soft_cap: 50,
+        hard_cap: 200,
+        release_after: Duration::from_millis(250),
+        backoff: Some(Duration::from_millis(100)),
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--items" => parsed.items = value(&mut args),
+            "--rate" => parsed.rate = value(&mut args),
+            "--burst-every" => parsed.burst_every = value(&mut args),
+            "--burst-size" => parsed.burst_size = value(&mut args),
+            "--fail-prob" => parsed.fail_prob = value(&mut args),
+            "--soft-cap" => parsed.soft_cap = value(&mut args),
+            "--hard-cap" => parsed.hard_cap = value(&mut args),
+            "--release-after-ms" => parsed.release_after = Duration::from_millis(value(&mut args)),
+            "--backoff-ms" => {
+                let ms: u64 = value(&mut args);
+                parsed.backoff = (ms > 0).then(|| Duration::from_millis(ms));
+            }
+            _ => usage(),
+        }
+    }
+    parsed
+}
+
+async fn produce(producer: RelaBufProducer<usize>, args: &Args) {
+    let pause = Duration::from_secs_f64(1.0 / args.rate.max(f64::MIN_POSITIVE));
+    for i in 0..args.items {
+        let burst = args.burst_every > 0 && i > 0 && i % args.burst_every == 0;
+        if !burst {
+            Timer::after(pause).await;
+        }
+        if producer.send(i).await.is_err() {
+            break;
+        }
+        if burst {
+            for _ in 1..args.burst_size {
+                if producer.send(i).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let args = parse_args();
+
+    let opts = RelaBufConfig {
+        soft_cap: args.soft_cap,
+        hard_cap: args.hard_cap,
+        release_after: args.release_after,
+        backoff: args.backoff.map(|initial_interval| ExponentialBackoff {
+            initial_interval,
+            ..ExponentialBackoff::default()
+        }),
+        ..RelaBufConfig::default()
+    };
+    let (buf, producer) = RelaBuf::with_producer(opts);
+
+    let started = Instant::now();
+    let consume = async {
+        let mut releases: HashMap<String, usize> = HashMap::new();
+        let (mut confirmed, mut retried) = (0, 0);
+        while let Ok(released) = buf.next().await {
+            *releases
+                .entry(format!("{:?}", released.reason))
+                .or_default() += 1;
+            let failed = released.reason != Reason::Term && fastrand::f64() < args.fail_prob;
+            println!(
+                "{:>8.3}s {:?} {} items after {:?}{}",
+                started.elapsed().as_secs_f64(),
+                released.reason,
+                released.items.len(),
+                released.elapsed,
+                if failed { " - failed, returning" } else { "" }
+            );
+            if failed {
+                retried += released.items.len();
+                released.return_on_err();
+            } else {
+                confirmed += released.items.len();
+                released.confirm();
+            }
+        }
+        println!("releases by reason: {:?}", releases);
+        println!(
+            "confirmed {} items, retried {} items in {:?}",
+            confirmed,
+            retried,
+            started.elapsed()
+        );
+    };
+
+    future::block_on(future::zip(produce(producer, &args), consume));
+}
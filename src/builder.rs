@@ -0,0 +1,104 @@
+//! Type-state builder for `RelaBufConfig`, `hard_cap` can only be set as headroom above an already
+//! set `soft_cap` and backoff settings only once retries are enabled, so a `soft_cap` over the
+//! `hard_cap`(which stalls intake) does not compile.
+//!
+//! `RelaBufConfig::builder().soft_cap(100).hard_cap_headroom(400).retries(RetryOrder::First)
+//!     .backoff(ExponentialBackoff::default()).build()`
+
+use crate::{BackoffScope, ExponentialBackoff, RelaBufConfig, RetryDelay, RetryOrder};
+use std::{marker::PhantomData, time::Duration};
+
+/// Caps left at their defaults
+pub struct NoCaps;
+/// `soft_cap` is set, `hard_cap` is twice as much until given a headroom
+pub struct SoftCap;
+/// Both caps are set
+pub struct Capped;
+
+/// Returned items are released again right away
+pub struct NoRetries;
+/// Returned items are queued in a `RetryOrder`, backoffs can be configured
+pub struct Retries;
+
+pub struct ConfigBuilder<T, C = NoCaps, R = NoRetries> {
+    opts: RelaBufConfig<T>,
+    _state: PhantomData<fn() -> (C, R)>,
+}
+
+impl<T> RelaBufConfig<T> {
+    pub fn builder() -> ConfigBuilder<T> {
+        ConfigBuilder {
+            opts: RelaBufConfig::default(),
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<T, C, R> ConfigBuilder<T, C, R> {
+    fn into_state<C2, R2>(self) -> ConfigBuilder<T, C2, R2> {
+        ConfigBuilder {
+            opts: self.opts,
+            _state: PhantomData,
+        }
+    }
+
+    pub fn release_after(mut self, release_after: Duration) -> Self {
+        self.opts.release_after = release_after;
+        self
+    }
+
+    pub fn strict_hard_cap(mut self) -> Self {
+        self.opts.strict_hard_cap = true;
+        self
+    }
+
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.opts.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    /// The rest of the settings can be filled in with `..builder.build()`
+    pub fn build(self) -> RelaBufConfig<T> {
+        self.opts
+    }
+}
+
+impl<T, R> ConfigBuilder<T, NoCaps, R> {
+    pub fn soft_cap(mut self, soft_cap: usize) -> ConfigBuilder<T, SoftCap, R> {
+        self.opts.soft_cap = soft_cap;
+        self.opts.hard_cap = soft_cap.saturating_mul(2);
+        self.into_state()
+    }
+}
+
+impl<T, R> ConfigBuilder<T, SoftCap, R> {
+    /// Sets `hard_cap` to `soft_cap` plus `headroom`
+    pub fn hard_cap_headroom(mut self, headroom: usize) -> ConfigBuilder<T, Capped, R> {
+        self.opts.hard_cap = self.opts.soft_cap.saturating_add(headroom);
+        self.into_state()
+    }
+}
+
+impl<T, C> ConfigBuilder<T, C, NoRetries> {
+    pub fn retries(mut self, order: RetryOrder) -> ConfigBuilder<T, C, Retries> {
+        self.opts.retry_order = order;
+        self.into_state()
+    }
+}
+
+impl<T, C> ConfigBuilder<T, C, Retries> {
+    pub fn backoff(mut self, backoff: ExponentialBackoff) -> Self {
+        self.opts.backoff = Some(backoff);
+        self
+    }
+
+    pub fn backoff_scope(mut self, scope: BackoffScope) -> Self {
+        self.opts.backoff_scope = scope;
+        self
+    }
+
+    pub fn retry_delay(mut self, retry_delay: RetryDelay) -> Self {
+        self.opts.retry_delay = Some(retry_delay);
+        self
+    }
+}
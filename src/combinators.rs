@@ -0,0 +1,300 @@
+use crate::{PinnedFut, Reason, RelaBuf, Released, Result, Terminal};
+use async_io::Timer;
+use futures_lite::{future, ready, Stream};
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    marker::PhantomData,
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Anything releasing batches the same way `RelaBuf::next` does, combinators wrap it
+pub trait Release<T> {
+    fn next(&self) -> PinnedFut<'static, Result<Released<T>, Terminal>>;
+
+    /// Keeps at least `interval` between consecutive releases
+    fn throttle(self, interval: Duration) -> Throttle<Self>
+    where
+        Self: Sized,
+    {
+        Throttle {
+            inner: self,
+            interval,
+            last: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Splits releases bigger than `size` into several ones of at most `size` items
+    fn chunk(self, size: usize) -> Chunk<T, Self>
+    where
+        Self: Sized,
+    {
+        Chunk {
+            inner: self,
+            size,
+            pending: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Turns every release into `U` while confirm/return keep resolving the original items
+    fn map_release<U, M: Fn(&[T]) -> U>(self, map: M) -> MapRelease<T, Self, M>
+    where
+        Self: Sized,
+    {
+        MapRelease {
+            inner: self,
+            map: Arc::new(map),
+            _items: PhantomData,
+        }
+    }
+
+    /// Releases from whichever of two sources is ready first, terminates once both did
+    fn merge<R: Release<T>>(self, other: R) -> Merge<T, Self, R>
+    where
+        Self: Sized,
+    {
+        Merge {
+            a: self,
+            b: other,
+            next: Arc::new(Mutex::new([None, None])),
+            done: Arc::new(Mutex::new([false, false])),
+        }
+    }
+}
+
+impl<T: 'static + Send + Sync + Debug> Release<T> for RelaBuf<T> {
+    fn next(&self) -> PinnedFut<'static, Result<Released<T>, Terminal>> {
+        RelaBuf::next(self)
+    }
+}
+
+/// `RelaBuf` as a `Stream` of releases, see `RelaBuf::into_stream`
+pub struct ReleaseStream<T> {
+    buf: RelaBuf<T>,
+    next: Option<PinnedFut<'static, Result<Released<T>, Terminal>>>,
+    done: bool,
+}
+
+impl<T: 'static + Send + Sync + Debug> RelaBuf<T> {
+    /// Yields what `next` does, the `Terminal` last before the stream ends, so releases compose
+    /// with stream combinators. Every release still has to be confirmed or returned.
+    pub fn into_stream(self) -> ReleaseStream<T> {
+        ReleaseStream {
+            buf: self,
+            next: None,
+            done: false,
+        }
+    }
+}
+
+impl<T> ReleaseStream<T> {
+    /// The buffer, e.g. for `stats` or `terminate`
+    pub fn get_ref(&self) -> &RelaBuf<T> {
+        &self.buf
+    }
+}
+
+impl<T: 'static + Send + Sync + Debug> Stream for ReleaseStream<T> {
+    type Item = Result<Released<T>, Terminal>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.done {
+            return Poll::Ready(None);
+        }
+        let buf = &this.buf;
+        let next = this.next.get_or_insert_with(|| buf.next());
+        let released = ready!(next.as_mut().poll(cx));
+        this.next = None;
+        this.done = released.is_err();
+        Poll::Ready(Some(released))
+    }
+}
+
+pub struct MapRelease<T, R, M> {
+    inner: R,
+    map: Arc<M>,
+    _items: PhantomData<fn() -> T>,
+}
+
+/// Release mapped by `map_release`, resolves against the items it was mapped from
+pub struct Mapped<T, U> {
+    pub id: u64,
+    pub reason: Reason,
+    pub elapsed: Duration,
+    pub items: U,
+    pub window: Option<Range<SystemTime>>,
+    pub term_cause: Option<Terminal>,
+    pub priority: Option<u64>,
+    released: Released<T>,
+}
+
+impl<T, U> Mapped<T, U> {
+    pub fn return_on_err(self) {
+        self.released.return_on_err()
+    }
+
+    pub fn return_after(self, delay: Duration) {
+        self.released.return_after(delay)
+    }
+
+    pub fn return_with_err(self, err: anyhow::Error) {
+        self.released.return_with_err(err)
+    }
+
+    pub fn resolve<E: Into<anyhow::Error>>(self, result: Result<(), E>) {
+        self.released.resolve(result)
+    }
+
+    pub fn resolve_each<E: Into<anyhow::Error>>(self, results: Vec<Result<(), E>>) {
+        self.released.resolve_each(results)
+    }
+
+    pub fn retry_failed<E: Into<anyhow::Error>>(self, failed: impl Fn(&T) -> Option<E>) {
+        self.released.retry_failed(failed)
+    }
+
+    pub fn confirm(&self) {
+        self.released.confirm()
+    }
+}
+
+impl<T: 'static + Send, U, R: Release<T>, M: 'static + Send + Sync + Fn(&[T]) -> U>
+    MapRelease<T, R, M>
+{
+    pub fn next(&self) -> PinnedFut<'static, Result<Mapped<T, U>, Terminal>> {
+        let next = self.inner.next();
+        let map = Arc::clone(&self.map);
+
+        Box::pin(async move {
+            let released = next.await?;
+            Ok(Mapped {
+                id: released.id,
+                reason: released.reason,
+                elapsed: released.elapsed,
+                items: map(&released.items),
+                window: released.window.clone(),
+                term_cause: released.term_cause.clone(),
+                priority: released.priority,
+                released,
+            })
+        })
+    }
+}
+
+pub struct Throttle<R> {
+    inner: R,
+    interval: Duration,
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+impl<T: 'static + Send, R: Release<T>> Release<T> for Throttle<R> {
+    fn next(&self) -> PinnedFut<'static, Result<Released<T>, Terminal>> {
+        let next = self.inner.next();
+        let interval = self.interval;
+        let last = Arc::clone(&self.last);
+
+        Box::pin(async move {
+            let wait = last
+                .lock()
+                .unwrap()
+                .map(|last| interval.saturating_sub(last.elapsed()));
+            if let Some(wait) = wait {
+                Timer::after(wait).await;
+            }
+
+            let released = next.await;
+            *last.lock().unwrap() = Some(Instant::now());
+            released
+        })
+    }
+}
+
+pub struct Chunk<T, R> {
+    inner: R,
+    size: usize,
+    pending: Arc<Mutex<VecDeque<Released<T>>>>,
+}
+
+impl<T: 'static + Send, R: Release<T>> Release<T> for Chunk<T, R> {
+    fn next(&self) -> PinnedFut<'static, Result<Released<T>, Terminal>> {
+        let next = self.inner.next();
+        let size = self.size;
+        let pending = Arc::clone(&self.pending);
+
+        Box::pin(async move {
+            // taken once polled, so a dropped `next` doesn't take a chunk along
+            let chunk = pending.lock().unwrap().pop_front();
+            if let Some(released) = chunk {
+                return Ok(released);
+            }
+            let mut chunks: VecDeque<_> = next.await?.chunks(size).into();
+            let first = chunks.pop_front().unwrap();
+            pending.lock().unwrap().extend(chunks);
+            Ok(first)
+        })
+    }
+}
+
+type Next<T> = PinnedFut<'static, Result<Released<T>, Terminal>>;
+
+pub struct Merge<T, A, B> {
+    a: A,
+    b: B,
+    /// What each source is being waited on for, kept across calls so the source losing a race
+    /// goes on where it was instead of being dropped
+    next: Arc<Mutex<[Option<Next<T>>; 2]>>,
+    done: Arc<Mutex<[bool; 2]>>,
+}
+
+impl<T: 'static + Send, A: Release<T>, B: Release<T>> Release<T> for Merge<T, A, B> {
+    fn next(&self) -> PinnedFut<'static, Result<Released<T>, Terminal>> {
+        let mut fresh = [Some(self.a.next()), Some(self.b.next())];
+        let next = Arc::clone(&self.next);
+        let done = Arc::clone(&self.done);
+
+        Box::pin(async move {
+            if *done.lock().unwrap() == [true, true] {
+                return fresh[0].take().unwrap().await;
+            }
+
+            let mut err = None;
+            let first = fastrand::usize(..2);
+            future::poll_fn(|cx| {
+                let mut next = next.lock().unwrap();
+                for i in [first, 1 - first] {
+                    if done.lock().unwrap()[i] {
+                        continue;
+                    }
+                    if next[i].is_none() {
+                        next[i] = fresh[i].take();
+                    }
+                    let source = match &mut next[i] {
+                        Some(source) => source,
+                        None => continue,
+                    };
+                    let released = match source.as_mut().poll(cx) {
+                        Poll::Ready(released) => released,
+                        Poll::Pending => continue,
+                    };
+                    next[i] = None;
+                    if released.is_ok() {
+                        return Poll::Ready(released);
+                    }
+                    done.lock().unwrap()[i] = true;
+                    err = Some(released);
+                }
+
+                if *done.lock().unwrap() == [true, true] {
+                    return Poll::Ready(err.take().unwrap());
+                }
+                Poll::Pending
+            })
+            .await
+        })
+    }
+}
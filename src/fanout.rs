@@ -0,0 +1,146 @@
+//! Fan-out of a release to several subscribers(e.g. replicas), each one getting a copy of the
+//! items and resolving it on its own
+
+use crate::{Reason, Released, NO_ERR};
+use anyhow::anyhow;
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// Gets the subscriber and error of every share failing under `Confirmation::Quorum`
+pub type OnShareFailure = Box<dyn Fn(usize, &anyhow::Error) + Send>;
+
+/// When a broadcast release counts as confirmed
+pub enum Confirmation {
+    /// Every subscriber has to confirm, a single return requeues the release for all of them so
+    /// replicas stay in lockstep
+    All,
+    /// Confirmed once `k` subscribers confirmed, the others' failures go to `on_failure` instead
+    /// of retrying for everyone. Only once `k` confirmations can't be reached anymore is the
+    /// release returned for all.
+    Quorum {
+        k: usize,
+        on_failure: Option<OnShareFailure>,
+    },
+}
+
+struct Broadcast<T> {
+    /// Taken once the release is resolved
+    released: Option<Released<T>>,
+    subscribers: usize,
+    confirmed: usize,
+    failed: usize,
+    confirmation: Confirmation,
+}
+
+impl<T> Broadcast<T> {
+    /// Confirmations the release needs
+    fn required(&self) -> usize {
+        match self.confirmation {
+            Confirmation::All => self.subscribers,
+            Confirmation::Quorum { k, .. } => k.min(self.subscribers),
+        }
+    }
+
+    /// Takes the release out once a failure leaves too few subscribers to confirm it, reports
+    /// the failure otherwise
+    fn fail(&mut self, subscriber: usize, err: &anyhow::Error) -> Option<Released<T>> {
+        self.failed += 1;
+        if self.subscribers - self.failed < self.required() {
+            return self.released.take();
+        }
+        if let Confirmation::Quorum {
+            on_failure: Some(on_failure),
+            ..
+        } = &self.confirmation
+        {
+            on_failure(subscriber, err);
+        }
+        None
+    }
+}
+
+/// A subscriber's copy of a broadcast release
+pub struct Share<T> {
+    pub id: u64,
+    pub reason: Reason,
+    pub items: Vec<T>,
+    pub window: Option<Range<SystemTime>>,
+    /// Which of the subscribers the share is for
+    pub subscriber: usize,
+    broadcast: Arc<Mutex<Broadcast<T>>>,
+}
+
+impl<T: Clone> Released<T> {
+    /// Hands a copy of the items to each of `subscribers`, the release is confirmed or returned
+    /// once they resolved their shares as `confirmation` requires
+    pub fn broadcast(self, subscribers: usize, confirmation: Confirmation) -> Vec<Share<T>> {
+        let required = match confirmation {
+            Confirmation::All => subscribers,
+            Confirmation::Quorum { k, .. } => k.min(subscribers),
+        };
+        if required == 0 {
+            self.confirm();
+            return vec![];
+        }
+
+        let (id, reason, items, window) = (
+            self.id,
+            self.reason,
+            self.items.clone(),
+            self.window.clone(),
+        );
+        let broadcast = Arc::new(Mutex::new(Broadcast {
+            released: Some(self),
+            subscribers,
+            confirmed: 0,
+            failed: 0,
+            confirmation,
+        }));
+        (0..subscribers)
+            .map(|subscriber| Share {
+                id,
+                reason,
+                items: items.clone(),
+                window: window.clone(),
+                subscriber,
+                broadcast: Arc::clone(&broadcast),
+            })
+            .collect()
+    }
+}
+
+impl<T> Share<T> {
+    pub fn confirm(self) {
+        let mut broadcast = self.broadcast.lock().unwrap();
+        broadcast.confirmed += 1;
+        if broadcast.confirmed == broadcast.required() {
+            if let Some(released) = broadcast.released.take() {
+                released.confirm();
+            }
+        }
+    }
+
+    /// Returns the release for every subscriber(under `Confirmation::Quorum` only once the
+    /// quorum is out of reach), shares resolved afterwards are ignored
+    pub fn return_on_err(self) {
+        let failed = self.fail(&anyhow!(NO_ERR));
+        if let Some(released) = failed {
+            released.return_on_err();
+        }
+    }
+
+    /// Like `return_on_err`, keeping the error in the buffer's error history
+    pub fn return_with_err(self, err: anyhow::Error) {
+        let failed = self.fail(&err);
+        if let Some(released) = failed {
+            released.return_with_err(err);
+        }
+    }
+
+    fn fail(&self, err: &anyhow::Error) -> Option<Released<T>> {
+        self.broadcast.lock().unwrap().fail(self.subscriber, err)
+    }
+}
@@ -0,0 +1,19 @@
+use crate::Discarded;
+
+/// Per-item lifecycle callbacks, e.g. for acknowledging every message to an upstream broker.
+///
+/// They run with the buffer locked and do nothing unless implemented.
+pub trait ItemHooks<T>: Send {
+    /// The item entered the buffer
+    fn on_enqueue(&self, _item: &T) {}
+
+    /// The item was released by `next` or acquired by `acquire`
+    fn on_release(&self, _item: &T) {}
+
+    fn on_confirm(&self, _item: &T) {}
+
+    /// The item was returned and is going to be released again
+    fn on_requeue(&self, _item: &T) {}
+
+    fn on_discard(&self, _item: &T, _reason: Discarded) {}
+}
@@ -1,36 +1,593 @@
 use anyhow::anyhow;
+use async_io::Timer;
 use backoff::backoff::Backoff;
-use flume::{bounded, Receiver, Sender};
-use futures_lite::Future;
+use flume::{bounded, Receiver, Sender, TrySendError};
+use futures_lite::{future, Future, Stream, StreamExt};
+use hooks::ItemHooks;
+use registry::{Registered, Registry};
+use smol_timeout::TimeoutExt;
 use std::{
+    cmp::Ordering,
+    collections::{btree_map::Entry, BTreeMap, HashMap, VecDeque},
+    ops::Range,
     pin::Pin,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use smol_timeout::TimeoutExt;
-use async_io::Timer;
+use storage::BatchStorage;
+use window::{wall_window, window_start, Panes, Windowing};
+
+pub mod builder;
+pub mod combinators;
+pub mod fanout;
+pub mod hooks;
+pub mod mem_size;
+pub mod registry;
+pub mod storage;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+#[cfg(test)]
+mod tests;
+pub mod window;
+
+/// Stands in for the error of a release returned without one
+const NO_ERR: &str = "returned without an error";
 
 pub type PinnedFut<'a, T = ()> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
-pub type Result<T> = anyhow::Result<T>;
+pub type LocalPinnedFut<'a, T = ()> = Pin<Box<dyn Future<Output = T> + 'a>>;
+pub type Result<T, E = anyhow::Error> = std::result::Result<T, E>;
+pub type GroupBy<T> = Box<dyn Fn(&T, &T) -> bool + Send>;
+pub type KeyBy<T> = Box<dyn Fn(&T) -> u64 + Send>;
+pub type SortBy<T> = Box<dyn Fn(&T, &T) -> Ordering + Send>;
+pub type Validate<T> = Box<dyn Fn(&T) -> Result<()> + Send>;
+pub type ValidateBatch<T> = Box<dyn Fn(&mut Vec<T>) -> Vec<Rejected<T>> + Send>;
+pub type OnConfirm<T> = Box<dyn Fn(&[T]) -> PinnedFut<'static, Result<()>> + Send>;
+pub type IntakeLimiter = Box<dyn Fn() -> PinnedFut<'static> + Send>;
+pub type RetryDelay = Box<dyn Fn(u32, &anyhow::Error) -> Option<Duration> + Send>;
+pub type Weigher<T> = Box<dyn Fn(&T) -> usize + Send>;
+pub type OnDrop<T> = Box<dyn Fn(Vec<T>, Discarded) + Send>;
+pub type OnFailure = Box<dyn Fn(&anyhow::Error, u32) -> FailureAction + Send>;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Reason {
     Time,
     Size,
     Term,
+    Group,
+    Retry,
+    Backpressure,
+    Age,
+    Window,
+    /// Addendum to an already released event time window
+    Late,
+    /// Items sent with `RelaBufProducer::send_urgent`, released on their own
+    Urgent,
+    /// Released by `RelaBuf::flush`
+    Flush,
+    /// An item's `deadline` is about to pass
+    Deadline,
+    /// Buffered items reached `soft_cap_weight`
+    Weight,
+}
+
+/// Lets only a fraction of incoming items into the buffer, the rest are skipped and counted
+#[derive(Debug, Clone, Copy)]
+pub enum Sampling {
+    /// Keeps the first of every N items
+    EveryNth(usize),
+    /// Keeps each item with the given probability(0.0..=1.0)
+    Fraction(f64),
+}
+
+/// Why items were discarded, passed to `RelaBufConfig::on_drop`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Discarded {
+    /// Left out by `Sampling`
+    Sampled,
+    /// Already confirmed within the `Dedup` window
+    Deduplicated,
+    /// An item with the same `coalesce_by` key was buffered already
+    Coalesced,
+    /// Failed `validate` or `validate_batch` while no `rejected` channel took it
+    Rejected,
+    /// Too late for their event time window while no `late` channel is set
+    TooLate,
+    /// Returned while `on_failure` decided on `FailureAction::Drop`
+    Failed,
+    /// Still buffered, in the channel or in `max_payload` pieces not handed out yet when the
+    /// `RelaBuf` was dropped, released but unresolved and acquired items are not included
+    Undelivered,
+}
+
+/// What `RelaBufConfig::on_failure` does with returned items
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureAction {
+    /// Releases them again once `retry_delay` or the backoff allow
+    Retry,
+    /// Discards them, `on_drop` gets them with `Discarded::Failed`
+    Drop,
+    /// Hands them over through `RelaBuf::take_exhausted` right away
+    DeadLetter,
+    /// Dead-letters them and terminates the buffer with `Terminal::Failed`
+    Terminate,
+}
+
+/// Where returned items go relative to freshly arrived ones in the next releases
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RetryOrder {
+    /// Retried items are released before any fresh ones
+    #[default]
+    First,
+    /// Retried items are released after all fresh ones
+    Last,
+    /// Alternates between retried and fresh items one by one
+    Mixed,
+}
+
+/// What a failed release delays
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BackoffScope {
+    /// The whole buffer waits out the backoff before releasing anything
+    #[default]
+    Buffer,
+    /// Only the returned items wait, each returned batch backs off on its own and is released
+    /// by itself with `Reason::Retry`, fresh items keep flowing
+    Batch,
+}
+
+/// Reports how far the source has been durably consumed, for resuming after a crash
+pub struct Checkpoint<T> {
+    /// Position of an item in its source(e.g. an offset), increasing in arrival order
+    pub cursor: Box<dyn Fn(&T) -> u64 + Send>,
+    /// Called with the highest cursor up to which every item has been confirmed, each time it
+    /// moves forward
+    pub on_checkpoint: Box<dyn Fn(u64) + Send>,
+}
+
+#[derive(Default)]
+struct Cursors {
+    held: BTreeMap<u64, usize>,
+    high: Option<u64>,
+    reported: Option<u64>,
+}
+
+impl Cursors {
+    fn seen(&mut self, cursor: u64) {
+        self.high = self.high.max(Some(cursor));
+    }
+
+    fn hold(&mut self, cursor: u64) {
+        *self.held.entry(cursor).or_default() += 1;
+    }
+
+    fn release(&mut self, cursor: u64) {
+        if let Entry::Occupied(mut held) = self.held.entry(cursor) {
+            *held.get_mut() -= 1;
+            if *held.get() == 0 {
+                held.remove();
+            }
+        }
+    }
+
+    /// Confirmed-through cursor if it moved since the last call
+    fn advance(&mut self) -> Option<u64> {
+        let through = match self.held.keys().next() {
+            Some(first) => first.checked_sub(1),
+            None => self.high,
+        }?;
+        if matches!(self.reported, Some(reported) if reported >= through) {
+            return None;
+        }
+        self.reported = Some(through);
+        Some(through)
+    }
+}
+
+/// Event timestamps for `Windowing::Tumbling` and `Windowing::Sliding`, so out-of-order sources
+/// still produce correctly bounded windows
+pub struct EventTime<T> {
+    pub time: Box<dyn Fn(&T) -> SystemTime + Send>,
+    /// How far behind the latest seen event time items may still arrive, a window closes once
+    /// this watermark passes its end(it only advances as items arrive)
+    pub max_delay: Duration,
+    /// How long after a window was released its late items are still released as an addendum
+    /// with `Reason::Late`, later ones go to the `late` channel
+    pub allowed_lateness: Duration,
+}
+
+/// Deadlines items carry, a release is forced with `Reason::Deadline` once the soonest one
+/// among the queued items is within `margin`.
+///
+/// Items are released in buffer order, `storage::Heap::by_key` with the reversed deadline puts
+/// the soonest ones first.
+pub struct Deadline<T> {
+    pub deadline: Box<dyn Fn(&T) -> SystemTime + Send>,
+    /// Time left to the consumer to deliver, the release is forced this long before the deadline
+    pub margin: Duration,
+}
+
+/// Partitions releases by the wall-clock aligned bucket of every item's own timestamp
+pub struct TimeBuckets<T> {
+    pub time: Box<dyn Fn(&T) -> SystemTime + Send>,
+    /// Length of the buckets, e.g. an hour for hourly files
+    pub period: Duration,
+}
+
+/// Drops items at intake whose key was confirmed recently, guarding against redelivery
+pub struct Dedup<T> {
+    pub key: Box<dyn Fn(&T) -> u64 + Send>,
+    /// Most confirmed keys remembered, the least recently confirmed are forgotten first
+    pub capacity: usize,
+    /// How long a confirmed key is remembered, only `capacity` bounds it when not set
+    pub ttl: Option<Duration>,
+}
+
+#[derive(Default)]
+struct DedupWindow {
+    confirmed: HashMap<u64, Instant>,
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl DedupWindow {
+    fn remember(&mut self, key: u64, capacity: usize) {
+        let now = Instant::now();
+        self.confirmed.insert(key, now);
+        self.order.push_back((key, now));
+        while self.confirmed.len() > capacity {
+            self.forget_oldest();
+        }
+        if self.order.len() > 2 * capacity.max(1) {
+            let confirmed = &self.confirmed;
+            self.order
+                .retain(|(key, at)| confirmed.get(key) == Some(at));
+        }
+    }
+
+    fn contains(&mut self, key: u64, ttl: Option<Duration>) -> bool {
+        if let Some(ttl) = ttl {
+            while matches!(self.order.front(), Some((_, at)) if at.elapsed() >= ttl) {
+                self.forget_oldest();
+            }
+        }
+        self.confirmed.contains_key(&key)
+    }
+
+    fn forget_oldest(&mut self) {
+        if let Some((key, at)) = self.order.pop_front() {
+            // keys confirmed again later have a newer entry further down the queue
+            if self.confirmed.get(&key) == Some(&at) {
+                self.confirmed.remove(&key);
+            }
+        }
+    }
+}
+
+/// Returned by `RelaBufProducer::send` under `strict_hard_cap` when the buffer is at its hard cap
+#[derive(Debug)]
+pub struct BufferFull;
+
+impl std::fmt::Display for BufferFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "buffer is full")
+    }
+}
+
+impl std::error::Error for BufferFull {}
+
+/// Why a buffer stopped releasing, returned by `next` once everything buffered was released
+#[derive(Debug, Clone)]
+pub enum Terminal {
+    /// Every producer is gone, `RelaBufProxy` included
+    ProducerClosed,
+    /// The `RelaBufProxy` source failed
+    ProducerError(Arc<anyhow::Error>),
+    /// `RelaBuf::terminate` was called
+    ConsumerTerminated,
+    /// The backoff reached its `max_elapsed_time`
+    RetriesExhausted,
+    /// `on_failure` decided on `FailureAction::Terminate` for this error
+    Failed(Arc<anyhow::Error>),
 }
 
+impl std::fmt::Display for Terminal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Terminal::ProducerClosed => write!(f, "producer closed"),
+            Terminal::ProducerError(err) => write!(f, "producer failed: {}", err),
+            Terminal::ConsumerTerminated => write!(f, "consumer terminated"),
+            Terminal::RetriesExhausted => write!(f, "retries exhausted"),
+            Terminal::Failed(err) => write!(f, "failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Terminal {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Terminal::ProducerError(err) | Terminal::Failed(err) => Some(err.as_ref().as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// An item that arrived after its event time window and the allowed lateness
+#[derive(Debug)]
+pub struct Late<T> {
+    pub item: T,
+    /// The window the item was meant for
+    pub window: Range<SystemTime>,
+}
+
+/// An item kept out of the buffer on its way to a side channel
+enum Diverted<T> {
+    Rejected(Sender<Rejected<T>>, Rejected<T>),
+    Late(Sender<Late<T>>, Late<T>),
+}
+
+impl<T> Diverted<T> {
+    fn try_send(self) {
+        match self {
+            Diverted::Rejected(tx, rejected) => {
+                let _ = tx.try_send(rejected);
+            }
+            Diverted::Late(tx, late) => {
+                let _ = tx.try_send(late);
+            }
+        }
+    }
+
+    async fn send(self) {
+        match self {
+            Diverted::Rejected(tx, rejected) => {
+                let _ = tx.send_async(rejected).await;
+            }
+            Diverted::Late(tx, late) => {
+                let _ = tx.send_async(late).await;
+            }
+        }
+    }
+}
+
+/// Where a recorded error came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorSource {
+    /// The `RelaBufProxy` source failed
+    Producer,
+    /// A release was returned with `return_with_err`
+    Consumer,
+}
+
+/// An entry of the bounded error history returned by `RelaBuf::errors`
+#[derive(Debug, Clone)]
+pub struct RecordedError {
+    pub at: SystemTime,
+    pub source: ErrorSource,
+    pub err: Arc<anyhow::Error>,
+}
+
+/// An item that failed intake or batch validation
+#[derive(Debug)]
+pub struct Rejected<T> {
+    pub item: T,
+    pub err: anyhow::Error,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Items taken in from the source
+    pub received: usize,
+    /// Items confirmed or committed
+    pub confirmed: usize,
+    /// Items returned or aborted, every attempt counts
+    pub returned: usize,
+    /// Items held by the buffer right now, returned ones waiting for a retry included
+    pub buffered: usize,
+    /// Items released but neither confirmed nor returned yet
+    pub in_flight: usize,
+    /// Average items taken in per second since the buffer was created
+    pub received_per_sec: f64,
+    /// Items dropped at intake by `Sampling`
+    pub skipped: usize,
+    /// Items that failed `validate` or `validate_batch`
+    pub rejected: usize,
+    /// Items dropped by `Dedup` as already confirmed
+    pub deduplicated: usize,
+    /// Items skipped by `coalesce_by` as already buffered
+    pub coalesced: usize,
+    /// Items given up on as dead letters, see `RelaBuf::take_exhausted`
+    pub exhausted: usize,
+    /// Items dropped by `on_failure`
+    pub failed: usize,
+    /// Items that arrived after their event time window and its allowed lateness
+    pub too_late: usize,
+    /// Items left behind when the buffer was dropped
+    pub undelivered: usize,
+    /// Items released within their `deadline` margin, in time but only just
+    pub near_misses: usize,
+    /// Items released after their `deadline` passed
+    pub missed_deadlines: usize,
+    /// Estimated bytes held by the buffered items and the spare capacity kept for more, see
+    /// `RelaBuf::memory_usage`
+    pub memory: usize,
+    /// `RelaBufConfig::name` of the buffer
+    pub name: Option<String>,
+}
+
+/// Adds the counts up, e.g. across a `registry::Registry`, the name is left out
+impl std::iter::Sum for Stats {
+    fn sum<I: Iterator<Item = Stats>>(iter: I) -> Self {
+        iter.fold(Stats::default(), |sum, stats| Stats {
+            received: sum.received + stats.received,
+            confirmed: sum.confirmed + stats.confirmed,
+            returned: sum.returned + stats.returned,
+            buffered: sum.buffered + stats.buffered,
+            in_flight: sum.in_flight + stats.in_flight,
+            received_per_sec: sum.received_per_sec + stats.received_per_sec,
+            skipped: sum.skipped + stats.skipped,
+            rejected: sum.rejected + stats.rejected,
+            deduplicated: sum.deduplicated + stats.deduplicated,
+            coalesced: sum.coalesced + stats.coalesced,
+            exhausted: sum.exhausted + stats.exhausted,
+            failed: sum.failed + stats.failed,
+            too_late: sum.too_late + stats.too_late,
+            undelivered: sum.undelivered + stats.undelivered,
+            near_misses: sum.near_misses + stats.near_misses,
+            missed_deadlines: sum.missed_deadlines + stats.missed_deadlines,
+            memory: sum.memory + stats.memory,
+            name: None,
+        })
+    }
+}
+
+impl Stats {
+    /// Items counted against a discard reason, including the ones routed to a channel
+    pub fn discarded(&self, reason: Discarded) -> usize {
+        match reason {
+            Discarded::Sampled => self.skipped,
+            Discarded::Deduplicated => self.deduplicated,
+            Discarded::Coalesced => self.coalesced,
+            Discarded::Rejected => self.rejected,
+            Discarded::TooLate => self.too_late,
+            Discarded::Failed => self.failed,
+            Discarded::Undelivered => self.undelivered,
+        }
+    }
+}
+
+/// Outcome of `RelaBuf::close_with_timeout`, what the buffer went through over its life next to
+/// what closing it left behind, so runbooks can verify a clean drain and tests can assert no loss
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShutdownReport {
+    /// Items taken in from the source
+    pub received: usize,
+    /// Items released, every retry and carried window copy counts
+    pub released: usize,
+    /// Items confirmed or committed
+    pub confirmed: usize,
+    /// Items returned or aborted, every attempt counts
+    pub retried: usize,
+    /// Items given up on as dead letters, see `RelaBuf::take_exhausted`
+    pub dead_lettered: usize,
+    /// Items that arrived after their event time window and its allowed lateness
+    pub expired: usize,
+    /// Items confirmed while closing
+    pub delivered: usize,
+    /// Items still buffered, released but unresolved or sent but not taken in at the deadline
+    pub stranded: usize,
+}
+
+impl ShutdownReport {
+    /// Whether nothing was stranded
+    pub fn is_clean(&self) -> bool {
+        self.stranded == 0
+    }
+}
+
+/// Adds the counts up, e.g. across a `registry::Registry`
+impl std::iter::Sum for ShutdownReport {
+    fn sum<I: Iterator<Item = ShutdownReport>>(iter: I) -> Self {
+        iter.fold(ShutdownReport::default(), |sum, report| ShutdownReport {
+            received: sum.received + report.received,
+            released: sum.released + report.released,
+            confirmed: sum.confirmed + report.confirmed,
+            retried: sum.retried + report.retried,
+            dead_lettered: sum.dead_lettered + report.dead_lettered,
+            expired: sum.expired + report.expired,
+            delivered: sum.delivered + report.delivered,
+            stranded: sum.stranded + report.stranded,
+        })
+    }
+}
+
+/// Items given up on once retries were exhausted, with what's needed to triage them
+#[derive(Debug)]
+pub struct DeadLetter<T> {
+    pub items: Vec<T>,
+    /// Error of the final return, `None` when returned without one
+    pub err: Option<Arc<anyhow::Error>>,
+    /// How many times the items were returned
+    pub attempts: u32,
+    /// When the items were first released
+    pub first_seen: SystemTime,
+    /// When the items were returned for the last time
+    pub last_attempt: SystemTime,
+}
+
+/// Items of a returned release with what they carry back into the buffer, the last flag backs
+/// them off on their own as under `BackoffScope::Batch`
+type Returned<T> = (
+    Vec<T>,
+    Option<backoff::ExponentialBackoff>,
+    u32,
+    Option<Range<SystemTime>>,
+    SystemTime,
+    bool,
+);
+
 #[derive(Debug)]
 struct Consumed<T> {
     elapsed: Duration,
     items: Vec<T>,
+    window: Option<Range<SystemTime>>,
+    backoff: Option<backoff::ExponentialBackoff>,
+    attempt: u32,
+    carried: usize,
+    late: bool,
+    /// When retried items were first released
+    first_seen: Option<SystemTime>,
+}
+
+/// What happened to a release, see `RelaBuf::subscribe_events`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Released,
+    Confirmed,
+    Returned,
+}
+
+/// Lightweight record of a release being handed out, confirmed or returned, for audit logs and
+/// dashboards
+#[derive(Debug, Clone)]
+pub struct ReleaseEvent {
+    pub kind: EventKind,
+    /// `Released::id` of the release
+    pub id: u64,
+    pub reason: Reason,
+    /// Items of the release, copies carried over from earlier windows left out
+    pub items: usize,
+    /// How many times the items were returned already under `BackoffScope::Batch`
+    pub attempt: u32,
+    /// When the items were first released
+    pub first_seen: SystemTime,
+    pub at: SystemTime,
 }
 
 pub struct Released<T> {
+    /// Increases with every release of a buffer, e.g. for naming the objects a batch is written to
+    pub id: u64,
     pub reason: Reason,
     pub elapsed: Duration,
     pub items: Vec<T>,
+    /// Time span of the window the items belong to under `window`
+    pub window: Option<Range<SystemTime>>,
+    /// Why the buffer is terminating, set on `Reason::Term` releases
+    pub term_cause: Option<Terminal>,
+    /// Highest `priority` among the items, e.g. for sending urgent batches down a faster path
+    pub priority: Option<u64>,
     state: Arc<Mutex<State<T>>>,
+    backoff: Option<backoff::ExponentialBackoff>,
+    /// How many times the items were returned already under `BackoffScope::Batch`
+    attempt: u32,
+    /// Leading items copied from earlier overlapping windows
+    carried: usize,
+    first_seen: SystemTime,
+    released_at: Instant,
+    /// Set once confirmed or returned, dropping it unresolved gives back its `max_in_flight` room
+    resolved: AtomicBool,
 }
 
 pub struct ExponentialBackoff {
@@ -63,199 +620,3237 @@ impl Default for ExponentialBackoff {
     }
 }
 
-impl<T> Released<T> {
-    pub fn return_on_err(self) {
-        let mut state = self.state.lock().unwrap();
-        state.return_on_err(self.items);
+impl ExponentialBackoff {
+    fn build(&self) -> backoff::ExponentialBackoff {
+        let mut backoff = backoff::ExponentialBackoff {
+            initial_interval: self.initial_interval,
+            randomization_factor: self.randomization_factor,
+            multiplier: self.multiplier,
+            max_interval: self.max_interval,
+            max_elapsed_time: self.max_elapsed_time,
+            ..backoff::ExponentialBackoff::default()
+        };
+        backoff.reset();
+        backoff
     }
 
-    pub fn confirm(&self) {
-        let mut state = self.state.lock().unwrap();
-        state.confirm();
+    /// Applies the parameters to a running backoff, keeping its progress within the new bounds
+    fn retune(&self, backoff: &mut backoff::ExponentialBackoff) {
+        backoff.initial_interval = self.initial_interval;
+        backoff.randomization_factor = self.randomization_factor;
+        backoff.multiplier = self.multiplier;
+        backoff.max_interval = self.max_interval;
+        backoff.max_elapsed_time = self.max_elapsed_time;
+        backoff.current_interval = backoff
+            .current_interval
+            .max(self.initial_interval)
+            .min(self.max_interval);
     }
 }
 
-pub struct RelaBufConfig {
-    pub release_after: Duration,
-    pub soft_cap: usize,
-    pub hard_cap: usize,
-    pub backoff: Option<ExponentialBackoff>,
+fn clone_backoff(b: &backoff::ExponentialBackoff) -> backoff::ExponentialBackoff {
+    backoff::ExponentialBackoff {
+        current_interval: b.current_interval,
+        initial_interval: b.initial_interval,
+        randomization_factor: b.randomization_factor,
+        multiplier: b.multiplier,
+        max_interval: b.max_interval,
+        start_time: b.start_time,
+        max_elapsed_time: b.max_elapsed_time,
+        clock: backoff::SystemClock::default(),
+    }
 }
 
-struct State<T> {
-    buffer: Vec<T>,
-    backoff: Option<backoff::ExponentialBackoff>,
-    opts: RelaBufConfig,
+struct BackoffState {
+    backoff: backoff::ExponentialBackoff,
+    retry_at: Option<Instant>,
+    /// Wakes the `next` of every buffer sharing it once a confirm ends the pause
+    wakers: Vec<Sender<()>>,
+}
 
-    last_ok_consume: Instant,
-    err: Option<anyhow::Error>,
+/// Backoff several buffers writing to the same downstream can share, so they pause together
+#[derive(Clone)]
+pub struct SharedBackoff(Arc<Mutex<BackoffState>>);
 
-    next_backoff: Option<Duration>,
-}
+impl SharedBackoff {
+    pub fn new(opts: &ExponentialBackoff) -> Self {
+        Self(Arc::new(Mutex::new(BackoffState {
+            backoff: opts.build(),
+            retry_at: None,
+            wakers: vec![],
+        })))
+    }
 
-impl<T> State<T> {
-    fn new(opts: RelaBufConfig) -> Self {
-        let backoff = opts
-            .backoff
-            .as_ref()
-            .map(|backoff| backoff::ExponentialBackoff {
-                initial_interval: backoff.initial_interval,
-                randomization_factor: backoff.randomization_factor,
-                multiplier: backoff.multiplier,
-                max_interval: backoff.max_interval,
-                max_elapsed_time: backoff.max_elapsed_time,
-                ..backoff::ExponentialBackoff::default()
-            });
+    fn subscribe(&self, wake_tx: Sender<()>) {
+        self.0.lock().unwrap().wakers.push(wake_tx);
+    }
 
-        Self {
-            buffer: vec![],
-            backoff,
-            opts,
-            last_ok_consume: Instant::now(),
-            err: None,
-            next_backoff: None,
+    /// Changes the parameters of the backoff for every buffer sharing it, a pause already under
+    /// way runs its course and the next one follows the new parameters
+    pub fn reconfigure(&self, opts: &ExponentialBackoff) {
+        opts.retune(&mut self.0.lock().unwrap().backoff);
+    }
+
+    /// Returns `false` once the backoff is exhausted
+    fn on_err(&self, since: Instant) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.retry_at = state.backoff.next_backoff().map(|delay| since + delay);
+        state.retry_at.is_some()
+    }
+
+    fn on_confirm(&self) {
+        let mut state = self.0.lock().unwrap();
+        let paused = state.retry_at.take().is_some();
+        state.backoff.reset();
+        if paused {
+            state.wakers.retain(|wake_tx| {
+                !matches!(wake_tx.try_send(()), Err(TrySendError::Disconnected(_)))
+            });
         }
     }
 
-    pub fn can_receive(&self) -> bool {
-        self.buffer.len() < self.opts.soft_cap && self.err.is_none()
+    fn is_waiting(&self) -> bool {
+        matches!(self.retry_at(), Some(retry_at) if Instant::now() < retry_at)
     }
 
-    pub fn add_item(&mut self, item: T) {
-        self.buffer.push(item)
+    fn retry_at(&self) -> Option<Instant> {
+        self.0.lock().unwrap().retry_at
     }
+}
 
-    pub fn return_on_err(&mut self, items: Vec<T>) {
-        self.buffer.extend(items);
-        if let Some(backoff) = &mut self.backoff {
-            self.next_backoff = backoff.next_backoff();
-        }
+impl<T> Released<T> {
+    pub fn return_on_err(self) {
+        self.give_back(None, None, false)
+    }
+
+    /// Returns the items, they are not released again before `delay` passes(e.g. a server's
+    /// `Retry-After`) even if the backoff would allow it
+    pub fn return_after(self, delay: Duration) {
+        self.give_back(Some(Instant::now() + delay), None, false)
     }
 
-    fn confirm(&mut self) {
-        if let Some(backoff) = &mut self.backoff {
-            self.next_backoff = None;
-            backoff.reset();
+    /// Returns the items like `return_on_err`, keeping `err` in the buffer's error history
+    pub fn return_with_err(self, err: anyhow::Error) {
+        self.give_back(None, Some(err), false)
+    }
+
+    /// Returns the items, `err` is kept in the buffer's error history
+    fn give_back(self, not_before: Option<Instant>, err: Option<anyhow::Error>, apart: bool) {
+        let err = err.map(Arc::new);
+        let (state, returned) = self.into_returned(apart);
+        let mut state = state.lock().unwrap();
+        if let Some(err) = &err {
+            state.record_err(ErrorSource::Consumer, Arc::clone(err));
         }
+        state.return_on_err(returned, not_before, err);
     }
 
-    fn set_err(&mut self, err: anyhow::Error) {
-        self.err = Some(err)
+    /// Confirms on `Ok`, on `Err` returns the items keeping the error like `return_with_err`
+    pub fn resolve<E: Into<anyhow::Error>>(self, result: Result<(), E>) {
+        match result {
+            Ok(()) => self.confirm(),
+            Err(err) => self.return_with_err(err.into()),
+        }
     }
 
-    fn is_ready(&self) -> Option<Reason> {
-        if self.buffer.is_empty() {
-            if self.err.is_some() {
-                return Some(Reason::Term);
+    /// Resolves every item on its own from `results`, aligned to `items`: items with `Ok` are
+    /// confirmed and the rest are returned together, keeping the first error. Items without a
+    /// result count as failed.
+    ///
+    /// Failed items next to confirmed ones back off on their own whatever the `backoff_scope`,
+    /// with an attempt counter and a backoff counting their own failures only, and the partial
+    /// confirm doesn't reset the buffer's backoff.
+    pub fn resolve_each<E: Into<anyhow::Error>>(mut self, results: Vec<Result<(), E>>) {
+        let mut results = results.into_iter().skip(self.carried);
+        let (mut confirmed, mut failed, mut err) = (vec![], vec![], None);
+        for item in self.items.drain(self.carried..) {
+            match results.next() {
+                Some(Ok(())) => confirmed.push(item),
+                Some(Err(e)) => {
+                    err.get_or_insert_with(|| e.into());
+                    failed.push(item);
+                }
+                None => failed.push(item),
             }
+        }
+        self.carried = 0;
+        if failed.is_empty() {
+            self.items = confirmed;
+            return self.confirm();
+        }
+        let apart = !confirmed.is_empty();
+        if apart {
+            let mut state = self.state.lock().unwrap();
+            state.in_flight = state.in_flight.saturating_sub(confirmed.len());
+            state.settle(&confirmed);
+            let event = ReleaseEvent {
+                items: confirmed.len(),
+                ..self.event(EventKind::Confirmed)
+            };
+            state.emit(event);
+        }
 
-            return None;
+        self.items = failed;
+        self.give_back(None, err, apart)
+    }
+
+    /// Returns just the items `failed` finds an error for, e.g. looked up in a bulk API's response,
+    /// and confirms the rest
+    pub fn retry_failed<E: Into<anyhow::Error>>(self, failed: impl Fn(&T) -> Option<E>) {
+        let results = self
+            .items
+            .iter()
+            .map(|item| failed(item).map_or(Ok(()), Err))
+            .collect();
+        self.resolve_each(results)
+    }
+
+    fn event(&self, kind: EventKind) -> ReleaseEvent {
+        ReleaseEvent {
+            kind,
+            id: self.id,
+            reason: self.reason,
+            items: self.items.len() - self.carried,
+            attempt: self.attempt,
+            first_seen: self.first_seen,
+            at: SystemTime::now(),
         }
-        if let Some(next_backoff) = self.next_backoff {
-            if self.last_ok_consume.elapsed() < next_backoff {
-                return None;
+    }
+
+    /// Carried copies are left out, their originals were released before
+    fn into_returned(mut self, apart: bool) -> (Arc<Mutex<State<T>>>, Returned<T>) {
+        let event = self.event(EventKind::Returned);
+        self.state.lock().unwrap().emit(event);
+        self.resolved.store(true, atomic::Ordering::Relaxed);
+        self.items.drain(..self.carried);
+        let returned = (
+            std::mem::take(&mut self.items),
+            self.backoff.take(),
+            self.attempt,
+            self.window.take(),
+            self.first_seen,
+            // items released apart once are retried apart
+            apart || self.reason == Reason::Retry,
+        );
+        (Arc::clone(&self.state), returned)
+    }
+
+    pub fn confirm(&self) {
+        self.resolved.store(true, atomic::Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        state.confirm(&self.items[self.carried..]);
+        state.observed.confirmed(self.released_at.elapsed());
+        state.emit(self.event(EventKind::Confirmed));
+    }
+
+    /// Runs the `on_confirm` hook and confirms afterwards, returns the release with the hook's
+    /// error like `return_with_err` if it fails.
+    ///
+    /// Plain `confirm` skips the hook.
+    pub async fn confirm_async(self) -> Result<()> {
+        if let Some(hook) = self.on_confirm() {
+            if let Err(err) = hook.await {
+                let returned =
+                    anyhow!("release {} returned, on_confirm failed: {:#}", self.id, err);
+                self.return_with_err(err);
+                return Err(returned);
             }
         }
+        self.confirm();
+        Ok(())
+    }
 
-        if self.err.is_some() {
-            return Some(Reason::Term);
-        }
+    fn on_confirm(&self) -> Option<PinnedFut<'static, Result<()>>> {
+        let state = self.state.lock().unwrap();
+        state.opts.on_confirm.as_ref().map(|hook| hook(&self.items))
+    }
 
-        if self.buffer.len() >= self.opts.soft_cap {
-            return Some(Reason::Size);
+    /// Splits the release into several ones of at most `size` items, each confirmed or returned
+    /// on its own
+    pub fn chunks(mut self, size: usize) -> Vec<Released<T>> {
+        let size = size.max(1);
+        let mut chunks = vec![];
+        while self.items.len() > size {
+            chunks.push(self.split_front(size));
         }
+        chunks.push(self);
+        chunks
+    }
 
-        if self.last_ok_consume.elapsed() >= self.opts.release_after {
-            return Some(Reason::Time);
+    /// Splits the release into pieces of the given lengths followed by whatever is left
+    fn split(mut self, lens: Vec<usize>) -> Vec<Released<T>> {
+        let mut pieces: Vec<_> = lens.into_iter().map(|len| self.split_front(len)).collect();
+        pieces.push(self);
+        pieces
+    }
+
+    /// Splits off the first `len` items as a release of their own
+    fn split_front(&mut self, len: usize) -> Released<T> {
+        let rest = self.items.split_off(len);
+        let carried = self.carried.min(len);
+        self.carried -= carried;
+        let items = std::mem::replace(&mut self.items, rest);
+        let mut state = self.state.lock().unwrap();
+        // ids keep following the order of the items
+        let id = state.next_id();
+        let priority = state.max_priority(&items);
+        self.priority = state.max_priority(&self.items);
+        drop(state);
+        Released {
+            id: std::mem::replace(&mut self.id, id),
+            reason: self.reason,
+            elapsed: self.elapsed,
+            items,
+            window: self.window.clone(),
+            term_cause: self.term_cause.clone(),
+            priority,
+            state: Arc::clone(&self.state),
+            backoff: self.backoff.as_ref().map(clone_backoff),
+            attempt: self.attempt,
+            carried,
+            first_seen: self.first_seen,
+            released_at: self.released_at,
+            resolved: AtomicBool::new(false),
         }
+    }
+}
 
-        None
+/// Gives back the `max_in_flight` room of a release dropped without being confirmed or
+/// returned, its items are lost
+impl<T> Drop for Released<T> {
+    fn drop(&mut self) {
+        if self.resolved.load(atomic::Ordering::Relaxed) {
+            return;
+        }
+        if let Ok(mut state) = self.state.lock() {
+            let items = self.items.len() - self.carried;
+            state.in_flight = state.in_flight.saturating_sub(items);
+            state.wake_consumer();
+            state.wake_progress();
+        }
     }
+}
 
-    fn consume(&mut self) -> Consumed<T> {
-        let elapsed = self.last_ok_consume.elapsed();
-        self.last_ok_consume = Instant::now();
-        Consumed {
-            elapsed,
-            items: self.buffer.drain(0..).collect(),
+impl<T: PartialEq> Released<T> {
+    /// Released items coalesced into runs of consecutive equal ones with their count
+    pub fn runs(&self) -> Vec<(&T, usize)> {
+        let mut runs: Vec<(&T, usize)> = vec![];
+        for item in &self.items {
+            match runs.last_mut() {
+                Some((last, count)) if *last == item => *count += 1,
+                _ => runs.push((item, 1)),
+            }
         }
+        runs
     }
 }
 
-pub struct RelaBuf<T> {
-    rx_buffer: Receiver<T>,
+/// Items handed out by `RelaBuf::acquire`, they stay in the buffer until `commit`.
+///
+/// Nothing else is released until it's committed or aborted, dropping it aborts.
+pub struct Acquired<T> {
+    pub id: u64,
+    pub reason: Reason,
+    pub elapsed: Duration,
+    len: usize,
     state: Arc<Mutex<State<T>>>,
+    acquired_at: Instant,
+    resolved: bool,
 }
 
-pub struct RelaBufProxy<T, F> {
-    tx_buffer: Sender<T>,
-    recv: F,
-}
+impl<T> Acquired<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
 
-impl<'a, T: 'static + Send + Sync + std::fmt::Debug, F: 'static + Send + Fn() -> PinnedFut<'a, Result<T>>> RelaBufProxy<T, F> {
-    pub async fn go(self) {
-        while !self.tx_buffer.is_disconnected() {
-            let item = (self.recv)().await;
-            if let Ok(item) = item {
-                if self.tx_buffer.send_async(item).await.is_err() {
-                    break
-                }
-                continue
-            }
-            break
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Borrows the acquired items, the buffer is locked until the returned guard is dropped
+    pub fn items(&self) -> AcquiredItems<'_, T> {
+        AcquiredItems {
+            state: self.state.lock().unwrap(),
+            len: self.len,
         }
     }
-}
 
-impl<'a, T: 'static + Send + Sync + std::fmt::Debug> RelaBuf<T> {
-    pub fn new<F: 'static + Send + Fn() -> PinnedFut<'a, Result<T>>>(
-        opts: RelaBufConfig,
-        recv: F,
-    ) -> (Self, RelaBufProxy<T, F>) {
-        let (tx_buffer, rx_buffer) = bounded::<T>(opts.hard_cap);
+    /// Removes the items from the buffer as confirmed
+    pub fn commit(mut self) {
+        self.resolved = true;
+        let mut state = self.state.lock().unwrap();
+        let event = self.event(EventKind::Confirmed, &state);
+        state.commit();
+        state.observed.confirmed(self.acquired_at.elapsed());
+        state.emit(event);
+    }
 
-        let state = Arc::new(Mutex::new(State::new(opts)));
+    /// Leaves the items buffered to be acquired again, backing off as a returned release would
+    pub fn abort(mut self) {
+        self.resolved = true;
+        let mut state = self.state.lock().unwrap();
+        let event = self.event(EventKind::Returned, &state);
+        state.emit(event);
+        state.abort()
+    }
 
-        (Self { rx_buffer, state }, RelaBufProxy{tx_buffer, recv})
+    /// Same events as a `Released`, attempts are counted by the buffer
+    fn event(&self, kind: EventKind, state: &State<T>) -> ReleaseEvent {
+        ReleaseEvent {
+            kind,
+            id: self.id,
+            reason: self.reason,
+            items: self.len,
+            attempt: 0,
+            first_seen: state.acquired_since.unwrap_or_else(SystemTime::now),
+            at: SystemTime::now(),
+        }
     }
+}
 
-    pub fn next(&self) -> PinnedFut<'static, Result<Released<T>>> {
-        let state = Arc::clone(&self.state);
-        let rx_buffer = self.rx_buffer.clone();
+/// Aborts an acquisition never resolved(on `?`, a panic or a cancelled task), so the buffer
+/// doesn't stay blocked on it
+impl<T> Drop for Acquired<T> {
+    fn drop(&mut self) {
+        if self.resolved {
+            return;
+        }
+        if let Ok(mut state) = self.state.lock() {
+            let event = self.event(EventKind::Returned, &state);
+            state.emit(event);
+            state.abort();
+        }
+    }
+}
 
-        Box::pin(async move {
-            let reason = loop {
-                if let Some(reason) = state.lock().unwrap().is_ready() {
-                    break reason;
-                }
+pub struct AcquiredItems<'a, T> {
+    state: MutexGuard<'a, State<T>>,
+    len: usize,
+}
 
-                let timeout_dur = Duration::from_millis(100);
-                if state.lock().unwrap().can_receive() {
-                    if let Some(r) = rx_buffer.recv_async().timeout(timeout_dur).await {
-                        match r {
-                            Ok(item) => state.lock().unwrap().add_item(item),
-                            Err(err) => state
-                                .lock()
-                                .unwrap()
-                                .set_err(anyhow!("cannot read from buffer channel: {}", err)),
-                        }
-                    }
-                } else {
-                    Timer::interval(timeout_dur).await;
-                }
-            };
+impl<T> AcquiredItems<'_, T> {
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.state.buffer.iter().take(self.len)
+    }
+}
 
-            let mut s = state.lock().unwrap();
-            let consumed = s.consume();
-            if reason == Reason::Term && consumed.items.is_empty() {
-                return Err(s.err.take().unwrap());
-            }
-            Ok(Released {
-                reason,
-                elapsed: consumed.elapsed,
-                items: consumed.items,
-                state: Arc::clone(&state),
+pub struct RelaBufConfig<T> {
+    pub release_after: Duration,
+    /// Times releases to wall-clock multiples of `release_after`(e.g. every :00/:15/:30/:45 with
+    /// 15s) instead of counting from the latest release
+    pub align_release: bool,
+    /// Releases with `Reason::Time` even when nothing is buffered, as a keep-alive for consumers
+    /// advancing watermarks on every batch
+    pub heartbeat: bool,
+    /// Releases just the items buffered longer than this with `Reason::Age`, fresher ones keep
+    /// accumulating toward a fuller batch
+    pub max_age: Option<Duration>,
+    /// Releases early so items make their deadlines, `window` panes and returned batches backing
+    /// off on their own are not considered
+    pub deadline: Option<Deadline<T>>,
+    /// Splits every release into one per time bucket, each with the bucket's span as its
+    /// `window`, so time partitioned storage never gets mixed buckets. It doesn't apply to
+    /// `window` releases.
+    pub time_buckets: Option<TimeBuckets<T>>,
+    /// Releases whole windows instead, `hard_cap` still bounds how much is buffered
+    pub window: Option<Windowing>,
+    /// Copies items into the overlapping windows they also belong to, usually `Clone::clone`,
+    /// without it windows don't overlap
+    pub carry: Option<fn(&T) -> T>,
+    /// Gives every key session windows of its own
+    pub window_key: Option<KeyBy<T>>,
+    /// Buckets items into time windows by their own timestamps instead of arrival time
+    pub event_time: Option<EventTime<T>>,
+    /// Receives items too late for their event time window(dropped when not set), a full
+    /// channel pauses intake
+    pub late: Option<Sender<Late<T>>>,
+    pub soft_cap: usize,
+    pub hard_cap: usize,
+    /// Releases with `Reason::Weight` once the buffered items(returned ones included) weigh this
+    /// much per `weigher`, alongside `soft_cap`. Doesn't apply under `window`, panes are released
+    /// as they close.
+    pub soft_cap_weight: Option<usize>,
+    /// Stops taking items in while buffered items(`window` panes included) and delayed retries
+    /// weigh this much, producers wait as under `hard_cap`
+    pub hard_cap_weight: Option<usize>,
+    /// Makes `RelaBufProducer::send` fail fast with `BufferFull` instead of waiting once
+    /// buffered, queued in the channel and released but unresolved items add up to `hard_cap`
+    pub strict_hard_cap: bool,
+    /// Bounds the items released but not yet confirmed or returned, `next` waits for them to
+    /// resolve before releasing more and never releases more than what's left
+    pub max_in_flight: Option<usize>,
+    /// Splits every release into several ones weighing at most this, keeping the order and the
+    /// attempt counter, an item heavier than it on its own is released alone
+    pub max_payload: Option<usize>,
+    /// Weight of an item against `max_payload` and the weight caps, what the `storage` says when
+    /// not set(`MemSized` goes by `MemSize`, the others count one per item)
+    pub weigher: Option<Weigher<T>>,
+    pub backoff: Option<ExponentialBackoff>,
+    /// Backoff shared with other buffers, takes precedence over `backoff`
+    pub shared_backoff: Option<SharedBackoff>,
+    /// Returned items are queued apart from fresh ones and released in this order
+    pub retry_order: RetryOrder,
+    /// Keeps fresh items back until the returned ones were released again, so downstreams
+    /// applying batches in order never get newer items first. Implies `RetryOrder::First`, under
+    /// `BackoffScope::Batch` returned batches are released one by one in the order they came back.
+    pub ordered_retries: bool,
+    pub backoff_scope: BackoffScope,
+    /// Decides the delay before returned items are released again from the attempt number(1 for
+    /// the first return) and the error given to `return_with_err`, `None` gives up as if the
+    /// backoff was exhausted. Takes precedence over `backoff`.
+    ///
+    /// Attempts are counted per returned batch under `BackoffScope::Batch` and per consecutive
+    /// returns without a confirm otherwise.
+    pub retry_delay: Option<RetryDelay>,
+    /// Decides what happens to returned items from the error(a placeholder for `return_on_err`)
+    /// and the attempt number before `retry_delay` or the backoff are consulted
+    pub on_failure: Option<OnFailure>,
+    /// Once producers are blocked on a full intake(`hard_cap` items waiting) releases right away
+    /// with `Reason::Backpressure` instead of waiting out the backoff
+    pub bypass_backoff_when_full: bool,
+    /// Returns `true` when two adjacent items belong to the same group.
+    ///
+    /// A release never mixes groups: as soon as an item from a different group is buffered
+    /// everything before it is released with `Reason::Group`.
+    pub group_by: Option<GroupBy<T>>,
+    /// Orders the items once right before they are released
+    pub sort_by: Option<SortBy<T>>,
+    /// Priority of an item, releases report the highest one they hold
+    pub priority: Option<KeyBy<T>>,
+    /// Orders the items by descending `priority` right before they are released, after `sort_by`
+    pub sort_by_priority: bool,
+    pub sampling: Option<Sampling>,
+    /// Checks every incoming item, failed ones never enter the buffer
+    pub validate: Option<Validate<T>>,
+    /// Checks or fixes up every batch right before it's released, e.g. dropping items that
+    /// conflict within it. The items it takes out of the batch and gives back go to `rejected`,
+    /// if that channel is full they are dropped. Copies carried over from earlier windows are
+    /// not passed to it.
+    pub validate_batch: Option<ValidateBatch<T>>,
+    /// Receives items that failed `validate` together with the error(dropped when not set).
+    ///
+    /// A full channel pauses intake until there is room.
+    pub rejected: Option<Sender<Rejected<T>>>,
+    /// Awaited after every item taken in, paces intake down to the limiter's rate.
+    ///
+    /// Plugs into any rate limiter, e.g. with `governor`:
+    /// `Box::new(move || { let l = limiter.clone(); Box::pin(async move { l.until_ready().await }) })`
+    pub intake_limiter: Option<IntakeLimiter>,
+    /// Makes an acknowledgment durable(e.g. commits offsets) before `confirm_async` confirms,
+    /// when it fails the release is returned instead
+    pub on_confirm: Option<OnConfirm<T>>,
+    pub checkpoint: Option<Checkpoint<T>>,
+    pub dedup: Option<Dedup<T>>,
+    /// Skips incoming items whose key matches an item still buffered, e.g. for batching "dirty
+    /// set" change notifications. Once released the key is free again.
+    pub coalesce_by: Option<KeyBy<T>>,
+    /// Called with the items the buffer discards and why, so none disappears without a trace.
+    /// It runs with the buffer locked.
+    pub on_drop: Option<OnDrop<T>>,
+    pub item_hooks: Option<Box<dyn ItemHooks<T>>>,
+    /// Backend holding buffered items, a `Vec` when not set
+    pub storage: Option<Box<dyn BatchStorage<T> + Send>>,
+    /// Shrinks the storage back to `soft_cap` once it stayed under a tenth of its capacity for
+    /// this long, returning the memory a burst grew it to
+    pub shrink_after: Option<Duration>,
+    /// How many recent errors `RelaBuf::errors` keeps
+    pub error_history: usize,
+    /// How long the buffer waits for an item when no timer is pending, timers themselves wake it
+    /// up right when they're due
+    pub poll_interval: Duration,
+    /// Tells buffers apart in `stats()` and error messages when a service runs several
+    pub name: Option<String>,
+}
+
+impl<T> Default for RelaBufConfig<T> {
+    fn default() -> Self {
+        Self {
+            release_after: Duration::from_secs(1),
+            align_release: false,
+            heartbeat: false,
+            max_age: None,
+            deadline: None,
+            time_buckets: None,
+            window: None,
+            carry: None,
+            window_key: None,
+            event_time: None,
+            late: None,
+            soft_cap: 1000,
+            hard_cap: 2000,
+            soft_cap_weight: None,
+            hard_cap_weight: None,
+            strict_hard_cap: false,
+            max_in_flight: None,
+            max_payload: None,
+            weigher: None,
+            backoff: None,
+            shared_backoff: None,
+            retry_order: RetryOrder::default(),
+            ordered_retries: false,
+            backoff_scope: BackoffScope::default(),
+            retry_delay: None,
+            on_failure: None,
+            bypass_backoff_when_full: false,
+            group_by: None,
+            sort_by: None,
+            priority: None,
+            sort_by_priority: false,
+            sampling: None,
+            validate: None,
+            validate_batch: None,
+            rejected: None,
+            intake_limiter: None,
+            on_confirm: None,
+            checkpoint: None,
+            dedup: None,
+            coalesce_by: None,
+            on_drop: None,
+            item_hooks: None,
+            storage: None,
+            shrink_after: None,
+            error_history: 10,
+            poll_interval: Duration::from_secs(1),
+            name: None,
+        }
+    }
+}
+
+impl<T> RelaBufConfig<T> {
+    /// Tuned caps, timers, backoff and overflow policy by name, so services can share settings.
+    ///
+    /// - `low-latency` releases small batches within 10ms and releases anyway once full
+    /// - `high-throughput` accumulates up to 10k items a second with a long backoff
+    /// - `lossy-telemetry` sheds load with `BufferFull` and drops batches failing 3 times
+    pub fn profile(name: &str) -> Result<Self> {
+        let opts = match name {
+            "low-latency" => Self {
+                release_after: Duration::from_millis(10),
+                soft_cap: 16,
+                hard_cap: 256,
+                backoff: Some(ExponentialBackoff {
+                    initial_interval: Duration::from_millis(10),
+                    max_interval: Duration::from_secs(1),
+                    ..ExponentialBackoff::default()
+                }),
+                bypass_backoff_when_full: true,
+                ..Self::default()
+            },
+            "high-throughput" => Self {
+                release_after: Duration::from_secs(1),
+                soft_cap: 10_000,
+                hard_cap: 50_000,
+                backoff: Some(ExponentialBackoff {
+                    initial_interval: Duration::from_millis(100),
+                    max_interval: Duration::from_secs(30),
+                    ..ExponentialBackoff::default()
+                }),
+                ..Self::default()
+            },
+            "lossy-telemetry" => Self {
+                release_after: Duration::from_secs(5),
+                soft_cap: 1000,
+                hard_cap: 2000,
+                strict_hard_cap: true,
+                backoff: Some(ExponentialBackoff {
+                    initial_interval: Duration::from_secs(1),
+                    ..ExponentialBackoff::default()
+                }),
+                backoff_scope: BackoffScope::Batch,
+                on_failure: Some(Box::new(|_, attempt| {
+                    if attempt < 3 {
+                        FailureAction::Retry
+                    } else {
+                        FailureAction::Drop
+                    }
+                })),
+                ..Self::default()
+            },
+            _ => return Err(anyhow!("unknown profile {}", name)),
+        };
+        Ok(opts)
+    }
+}
+
+/// Storage of the buffered items, the default `Vec` is kept apart so it's `Send` exactly when the
+/// items are and a `LocalRelaBuf` can hold items that aren't
+enum Buffer<T> {
+    Vec(Vec<T>),
+    Custom(Box<dyn BatchStorage<T> + Send>),
+}
+
+impl<T> Buffer<T> {
+    fn storage(&self) -> &dyn BatchStorage<T> {
+        match self {
+            Buffer::Vec(items) => items,
+            Buffer::Custom(storage) => storage.as_ref(),
+        }
+    }
+
+    fn storage_mut(&mut self) -> &mut dyn BatchStorage<T> {
+        match self {
+            Buffer::Vec(items) => items,
+            Buffer::Custom(storage) => storage.as_mut(),
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        self.storage_mut().push(item)
+    }
+
+    fn len(&self) -> usize {
+        self.storage().len()
+    }
+
+    fn drain_up_to(&mut self, n: usize) -> Vec<T> {
+        self.storage_mut().drain_up_to(n)
+    }
+
+    fn requeue_front(&mut self, items: Vec<T>) {
+        self.storage_mut().requeue_front(items)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        self.storage().iter()
+    }
+
+    fn last(&self) -> Option<&T> {
+        self.storage().last()
+    }
+
+    fn capacity(&self) -> usize {
+        self.storage().capacity()
+    }
+
+    fn shrink_to(&mut self, capacity: usize) {
+        self.storage_mut().shrink_to(capacity)
+    }
+}
+
+/// Items of a returned batch backing off on their own under `BackoffScope::Batch`
+struct Delayed<T> {
+    items: Vec<T>,
+    backoff: Option<backoff::ExponentialBackoff>,
+    attempt: u32,
+    window: Option<Range<SystemTime>>,
+    retry_at: Option<Instant>,
+    first_seen: SystemTime,
+}
+
+impl<T> Delayed<T> {
+    fn is_due(&self) -> bool {
+        !matches!(self.retry_at, Some(retry_at) if Instant::now() < retry_at)
+    }
+}
+
+/// What `RelaBuf::suggest_config` bases its advice on
+struct Observed {
+    since: Instant,
+    releases: usize,
+    released: usize,
+    confirms: u32,
+    confirm_time: Duration,
+}
+
+impl Observed {
+    fn released(&mut self, items: usize) {
+        self.releases += 1;
+        self.released += items;
+    }
+
+    fn confirmed(&mut self, latency: Duration) {
+        self.confirms += 1;
+        self.confirm_time += latency;
+    }
+
+    fn confirm_latency(&self) -> Option<Duration> {
+        (self.confirms > 0).then(|| self.confirm_time / self.confirms)
+    }
+}
+
+/// Channel of a producer made with `RelaBufProducer::lane`
+struct Lane<T> {
+    rx: Receiver<T>,
+    weight: usize,
+}
+
+struct State<T> {
+    buffer: Buffer<T>,
+    /// When fresh items arrived in release order, kept only with `max_age` set
+    arrivals: VecDeque<Instant>,
+    retry: VecDeque<T>,
+    /// Span of the windows returned into `retry`
+    retry_window: Option<Range<SystemTime>>,
+    /// When the earliest of the retried items was first released
+    retry_since: Option<SystemTime>,
+    /// Leading buffered items handed out by `RelaBuf::acquire`, nothing is released meanwhile
+    acquired: Option<usize>,
+    acquired_since: Option<SystemTime>,
+    delayed: Vec<Delayed<T>>,
+    /// Items from `send_urgent` going out with the very next release
+    urgent: Vec<T>,
+    /// Items `RelaBuf::flush` still has to release
+    flush_left: usize,
+    panes: Option<Panes<T>>,
+    exhausted: Vec<DeadLetter<T>>,
+    backoff: Option<SharedBackoff>,
+    opts: RelaBufConfig<T>,
+
+    last_ok_consume: Instant,
+    /// Returns since the latest confirm, as handed to `retry_delay`
+    attempts: u32,
+    /// Wall-clock `release_after` window of the latest release under `align_release`
+    aligned: u128,
+    err: Option<Terminal>,
+    /// Error the `RelaBufProxy` source stopped with
+    producer_err: Option<Arc<anyhow::Error>>,
+    errors: VecDeque<RecordedError>,
+    not_before: Option<Instant>,
+    last_id: u64,
+    /// Released items neither confirmed nor returned yet
+    in_flight: usize,
+    /// Producers waiting in `poll_ready` for room to send
+    space_waiters: Vec<Waker>,
+    /// `flush` and `close` waiting for items to be released or resolved
+    progress_waiters: Vec<Waker>,
+    /// Signalled to get `next` out of waiting for intake
+    wake_tx: Sender<()>,
+    wake_rx: Receiver<()>,
+
+    group_end: Option<usize>,
+
+    seen: usize,
+    stats: Stats,
+    observed: Observed,
+    /// Since when the storage holds less than a tenth of its capacity
+    sparse_since: Option<Instant>,
+    lanes: Vec<Lane<T>>,
+    /// Channel whose turn it is, the main one first, and how many items it gave this turn
+    turn: usize,
+    served: usize,
+    cursors: Cursors,
+    dedup: DedupWindow,
+    /// How many buffered items have every `coalesce_by` key
+    buffered_keys: HashMap<u64, usize>,
+    /// Soonest `deadline` among the queued items
+    next_deadline: Option<SystemTime>,
+    /// What the queued items weigh, kept only under the weight caps
+    weight: usize,
+    /// `RelaBuf::subscribe_events` observers
+    observers: Vec<Sender<ReleaseEvent>>,
+}
+
+impl<T> State<T> {
+    fn new(opts: RelaBufConfig<T>, buffer: Buffer<T>) -> Self {
+        let backoff = opts
+            .shared_backoff
+            .clone()
+            .or_else(|| opts.backoff.as_ref().map(SharedBackoff::new));
+        let aligned = wall_window(SystemTime::now(), opts.release_after);
+        let (wake_tx, wake_rx) = bounded(1);
+        if let Some(backoff) = &backoff {
+            backoff.subscribe(wake_tx.clone());
+        }
+
+        Self {
+            buffer,
+            arrivals: VecDeque::new(),
+            retry: VecDeque::new(),
+            retry_window: None,
+            retry_since: None,
+            acquired: None,
+            acquired_since: None,
+            delayed: vec![],
+            urgent: vec![],
+            flush_left: 0,
+            panes: opts.window.map(|window| {
+                let event_time = opts
+                    .event_time
+                    .as_ref()
+                    .map(|event| (event.max_delay, event.allowed_lateness));
+                Panes::new(window, opts.carry, event_time)
+            }),
+            exhausted: vec![],
+            backoff,
+            opts,
+            last_ok_consume: Instant::now(),
+            attempts: 0,
+            aligned,
+            err: None,
+            producer_err: None,
+            errors: VecDeque::new(),
+            not_before: None,
+            last_id: 0,
+            in_flight: 0,
+            space_waiters: vec![],
+            progress_waiters: vec![],
+            wake_tx,
+            wake_rx,
+            group_end: None,
+            seen: 0,
+            stats: Stats::default(),
+            sparse_since: None,
+            lanes: vec![],
+            turn: 0,
+            served: 0,
+            observed: Observed {
+                since: Instant::now(),
+                releases: 0,
+                released: 0,
+                confirms: 0,
+                confirm_time: Duration::ZERO,
+            },
+            cursors: Cursors::default(),
+            dedup: DedupWindow::default(),
+            buffered_keys: HashMap::new(),
+            next_deadline: None,
+            weight: 0,
+            observers: vec![],
+        }
+    }
+
+    pub fn can_receive(&self) -> bool {
+        (self.queued() < self.opts.soft_cap || self.panes.is_some())
+            && self.len() < self.opts.hard_cap.max(self.opts.soft_cap)
+            && self.err.is_none()
+            && self.weight_room()
+    }
+
+    /// Whether the weight caps let more items in
+    fn weight_room(&self) -> bool {
+        if matches!(self.opts.soft_cap_weight, Some(cap) if self.weight >= cap) {
+            return self.panes.is_some();
+        }
+        match self.opts.hard_cap_weight {
+            Some(cap) => {
+                let delayed = self.delayed.iter().flat_map(|delayed| delayed.items.iter());
+                self.weight + self.weigh_all(delayed) < cap
+            }
+            None => true,
+        }
+    }
+
+    fn weigh(&self, item: &T) -> usize {
+        match &self.opts.weigher {
+            Some(weigh) => weigh(item),
+            None => self.buffer.storage().weigh(item),
+        }
+    }
+
+    /// What `items` weigh against the weight caps, nothing when there are none
+    fn weigh_all<'a>(&self, items: impl IntoIterator<Item = &'a T>) -> usize
+    where
+        T: 'a,
+    {
+        if self.opts.soft_cap_weight.is_none() && self.opts.hard_cap_weight.is_none() {
+            return 0;
+        }
+        items.into_iter().map(|item| self.weigh(item)).sum()
+    }
+
+    fn intake(&mut self, item: T) -> Option<Diverted<T>> {
+        self.stats.received += 1;
+        let cursor = self.cursor(&item);
+        if let Some(cursor) = cursor {
+            self.cursors.seen(cursor);
+        }
+        if let Some(dedup) = &self.opts.dedup {
+            if self.dedup.contains((dedup.key)(&item), dedup.ttl) {
+                self.stats.deduplicated += 1;
+                self.discard(vec![item], Discarded::Deduplicated);
+                return None;
+            }
+        }
+        let coalesce_key = self.opts.coalesce_by.as_ref().map(|key| key(&item));
+        if let Some(key) = coalesce_key {
+            if self.buffered_keys.contains_key(&key) {
+                self.stats.coalesced += 1;
+                self.discard(vec![item], Discarded::Coalesced);
+                return None;
+            }
+        }
+        if let Some(validate) = &self.opts.validate {
+            if let Err(err) = validate(&item) {
+                self.stats.rejected += 1;
+                match self.opts.rejected.clone() {
+                    Some(tx) => return Some(Diverted::Rejected(tx, Rejected { item, err })),
+                    None => {
+                        self.discard(vec![item], Discarded::Rejected);
+                        return None;
+                    }
+                }
+            }
+        }
+        match self.add_item(item) {
+            Ok(true) => {
+                if let Some(cursor) = cursor {
+                    self.cursors.hold(cursor);
+                }
+                if let Some(key) = coalesce_key {
+                    *self.buffered_keys.entry(key).or_default() += 1;
+                }
+            }
+            Ok(false) => {}
+            Err(late) => match self.opts.late.clone() {
+                Some(tx) => return Some(Diverted::Late(tx, late)),
+                None => self.discard(vec![late.item], Discarded::TooLate),
+            },
+        }
+        None
+    }
+
+    fn discard(&self, items: Vec<T>, reason: Discarded) {
+        if let Some(hooks) = &self.opts.item_hooks {
+            for item in &items {
+                hooks.on_discard(item, reason);
+            }
+        }
+        if let Some(on_drop) = &self.opts.on_drop {
+            on_drop(items, reason)
+        }
+    }
+
+    /// Takes out every item nobody is going to release anymore, acquired ones stay in place
+    fn take_undelivered(&mut self) -> Vec<T> {
+        self.wake_progress();
+        let mut items = std::mem::take(&mut self.urgent);
+        items.extend(self.retry.drain(..));
+        let mut fresh = self.buffer.drain_up_to(self.buffer.len());
+        let acquired = self.acquired.unwrap_or(0).min(fresh.len());
+        self.buffer.requeue_front(fresh.drain(..acquired).collect());
+        self.arrivals.drain(acquired.min(self.arrivals.len())..);
+        self.weight = self.weigh_all(self.buffer.iter());
+        items.extend(fresh);
+        items.extend(self.delayed.drain(..).flat_map(|delayed| delayed.items));
+        if let Some(panes) = &mut self.panes {
+            while let Some(mut pane) = panes.pop() {
+                items.extend(pane.items.drain(pane.carried..));
+            }
+        }
+        items
+    }
+
+    fn cursor(&self, item: &T) -> Option<u64> {
+        self.opts
+            .checkpoint
+            .as_ref()
+            .map(|checkpoint| (checkpoint.cursor)(item))
+    }
+
+    /// Whether the item was buffered, too late ones are handed back
+    pub fn add_item(&mut self, item: T) -> Result<bool, Late<T>> {
+        if !self.sample() {
+            self.stats.skipped += 1;
+            self.discard(vec![item], Discarded::Sampled);
+            return Ok(false);
+        }
+        let weight = self.weigh_all([&item]);
+        if let Some(panes) = &mut self.panes {
+            let key = self.opts.window_key.as_ref().map_or(0, |key| key(&item));
+            let at = self
+                .opts
+                .event_time
+                .as_ref()
+                .map(|event| (event.time)(&item));
+            match panes.push(item, key, at) {
+                Ok(item) => {
+                    if let Some(hooks) = &self.opts.item_hooks {
+                        hooks.on_enqueue(item);
+                    }
+                    self.weight += weight;
+                }
+                Err((item, window)) => {
+                    self.stats.too_late += 1;
+                    return Err(Late { item, window });
+                }
+            }
+            return Ok(true);
+        }
+        if let Some(hooks) = &self.opts.item_hooks {
+            hooks.on_enqueue(&item);
+        }
+        if let Some(deadline) = &self.opts.deadline {
+            let due = (deadline.deadline)(&item);
+            self.next_deadline = Some(self.next_deadline.map_or(due, |next| next.min(due)));
+        }
+        self.weight += weight;
+        self.buffer.push(item);
+        if self.opts.max_age.is_some() {
+            self.arrivals.push_back(Instant::now());
+        }
+        if self.group_end.is_some() || self.opts.group_by.is_none() {
+            return Ok(true);
+        }
+        if self.retry.is_empty() || self.retry_order() == RetryOrder::First {
+            let same_group = self.opts.group_by.as_ref().unwrap();
+            let prev = match self.buffer.len() {
+                1 => self.retry.back(),
+                len => self.buffer.iter().nth(len - 2),
+            };
+            if let (Some(prev), Some(last)) = (prev, self.buffer.last()) {
+                if !same_group(prev, last) {
+                    self.group_end = Some(self.queued() - 1);
+                }
+            }
+        } else {
+            self.update_group_end();
+        }
+        Ok(true)
+    }
+
+    pub fn return_on_err(
+        &mut self,
+        (items, backoff, attempt, window, first_seen, apart): Returned<T>,
+        not_before: Option<Instant>,
+        err: Option<Arc<anyhow::Error>>,
+    ) {
+        self.in_flight = self.in_flight.saturating_sub(items.len());
+        self.stats.returned += items.len();
+        self.wake_consumer();
+        self.wake_progress();
+        let apart = apart || self.opts.backoff_scope == BackoffScope::Batch;
+        let attempt = match apart {
+            true => attempt + 1,
+            false => {
+                self.attempts += 1;
+                self.attempts
+            }
+        };
+        let dead_letter = |items| DeadLetter {
+            items,
+            err: err.clone(),
+            attempts: attempt,
+            first_seen,
+            last_attempt: SystemTime::now(),
+        };
+        if matches!(self.err, Some(Terminal::RetriesExhausted)) {
+            self.give_up(dead_letter(items));
+            return;
+        }
+        match self.failure_action(attempt, err.as_deref()) {
+            FailureAction::Retry => {}
+            FailureAction::Drop => {
+                self.stats.failed += items.len();
+                self.settle_cursors(&items);
+                self.discard(items, Discarded::Failed);
+                return;
+            }
+            FailureAction::DeadLetter => {
+                self.give_up(dead_letter(items));
+                return;
+            }
+            FailureAction::Terminate => {
+                let cause = err.clone().unwrap_or_else(|| Arc::new(anyhow!(NO_ERR)));
+                self.set_err(Terminal::Failed(cause));
+                self.give_up(dead_letter(items));
+                return;
+            }
+        }
+        let custom_delay = self.custom_delay(attempt, err.as_deref());
+
+        if apart {
+            let mut backoff = match custom_delay {
+                Some(_) => None,
+                None => backoff.or_else(|| self.opts.backoff.as_ref().map(|b| b.build())),
+            };
+            let delay = custom_delay.or_else(|| backoff.as_mut().map(|b| b.next_backoff()));
+            if let Some(None) = delay {
+                self.set_err(Terminal::RetriesExhausted);
+                self.give_up(dead_letter(items));
+                return;
+            }
+            let retry_at = delay
+                .flatten()
+                .map(|delay| Instant::now() + delay)
+                .max(not_before);
+            self.each_item(&items, |hooks, item| hooks.on_requeue(item));
+            self.track_keys(&items, true);
+            self.delayed.push(Delayed {
+                items,
+                backoff,
+                attempt,
+                window,
+                retry_at,
+                first_seen,
+            });
+            return;
+        }
+
+        if !self.back_off(custom_delay) {
+            self.set_err(Terminal::RetriesExhausted);
+            self.give_up(dead_letter(items));
+            return;
+        }
+        self.each_item(&items, |hooks, item| hooks.on_requeue(item));
+        self.track_keys(&items, true);
+        self.weight += self.weigh_all(&items);
+        self.retry.extend(items);
+        self.retry_since = Some(
+            self.retry_since
+                .map_or(first_seen, |since| since.min(first_seen)),
+        );
+        self.retry_window = match (self.retry_window.take(), window) {
+            (Some(a), Some(b)) => Some(a.start.min(b.start)..a.end.max(b.end)),
+            (a, b) => a.or(b),
+        };
+        self.update_group_end();
+        self.update_deadline();
+        self.not_before = self.not_before.max(not_before);
+    }
+
+    fn each_item<'i>(
+        &self,
+        items: impl IntoIterator<Item = &'i T>,
+        hook: impl Fn(&dyn ItemHooks<T>, &T),
+    ) where
+        T: 'i,
+    {
+        if let Some(hooks) = &self.opts.item_hooks {
+            for item in items {
+                hook(hooks.as_ref(), item);
+            }
+        }
+    }
+
+    /// Delay `retry_delay` picks for a failed attempt, `Some(None)` gives up
+    fn custom_delay(&self, attempt: u32, err: Option<&anyhow::Error>) -> Option<Option<Duration>> {
+        self.opts.retry_delay.as_ref().map(|retry_delay| match err {
+            Some(err) => retry_delay(attempt, err),
+            None => retry_delay(attempt, &anyhow!(NO_ERR)),
+        })
+    }
+
+    fn failure_action(&self, attempt: u32, err: Option<&anyhow::Error>) -> FailureAction {
+        match &self.opts.on_failure {
+            Some(on_failure) => match err {
+                Some(err) => on_failure(err, attempt),
+                None => on_failure(&anyhow!(NO_ERR), attempt),
+            },
+            None => FailureAction::Retry,
+        }
+    }
+
+    /// Holds the whole buffer back after a failed attempt, `false` once retries are exhausted
+    fn back_off(&mut self, custom_delay: Option<Option<Duration>>) -> bool {
+        match custom_delay {
+            Some(delay) => {
+                let retry_at = delay.map(|delay| Instant::now() + delay);
+                self.not_before = self.not_before.max(retry_at);
+                delay.is_some()
+            }
+            None => !matches!(&self.backoff, Some(b) if !b.on_err(self.last_ok_consume)),
+        }
+    }
+
+    /// Marks the leading buffered items as acquired, they stay where they are until `commit`
+    fn acquire(&mut self, reason: Reason) -> usize {
+        // urgent and returned items are acquired from the front of the buffer like the rest
+        let mut front = std::mem::take(&mut self.urgent);
+        let urgent = front.len();
+        let due = match reason {
+            Reason::Retry => self.due_delayed(),
+            _ => None,
+        };
+        let delayed = due.map_or(0, |due| {
+            let delayed = self.delayed.remove(due);
+            front.extend(delayed.items);
+            front.len() - urgent
+        });
+        self.weight += self.weigh_all(&front);
+        front.extend(self.retry.drain(..));
+        if !front.is_empty() {
+            if self.opts.max_age.is_some() {
+                let now = Instant::now();
+                for _ in 0..front.len() {
+                    self.arrivals.push_front(now);
+                }
+            }
+            self.buffer.requeue_front(front);
+            self.retry_window = None;
+            if let Some(since) = self.retry_since.take() {
+                self.acquired_since = Some(since);
+            }
+            self.update_group_end();
+            self.update_deadline();
+        }
+        let len = match reason {
+            Reason::Urgent => urgent,
+            Reason::Retry => delayed,
+            Reason::Age => self.over_age(),
+            Reason::Group => self.group_end.unwrap_or(0),
+            _ => self.buffer.len(),
+        }
+        .min(self.buffer.len());
+        self.acquired = Some(len);
+        self.acquired_since.get_or_insert_with(SystemTime::now);
+        self.each_item(self.buffer.iter().take(len), |hooks, item| {
+            hooks.on_release(item)
+        });
+        len
+    }
+
+    fn commit(&mut self) {
+        let len = self.acquired.take().unwrap_or(0);
+        self.acquired_since = None;
+        let items = self.take_split(0, len);
+        self.track_keys(&items, false);
+        self.flush_left = self.flush_left.saturating_sub(items.len());
+        self.update_group_end();
+        self.update_deadline();
+        self.resolve(&items);
+    }
+
+    /// Leaves the acquired items in place and backs off as if they were returned
+    fn abort(&mut self) {
+        let len = self.acquired.take().unwrap_or(0);
+        self.stats.returned += len;
+        self.wake_consumer();
+        self.attempts += 1;
+        let exhausted = matches!(self.err, Some(Terminal::RetriesExhausted)) || {
+            let custom_delay = self.custom_delay(self.attempts, None);
+            !self.back_off(custom_delay)
+        };
+        if exhausted {
+            self.set_err(Terminal::RetriesExhausted);
+            let items = self.take_split(0, len);
+            self.update_group_end();
+            self.update_deadline();
+            let first_seen = self.acquired_since.take();
+            self.give_up(DeadLetter {
+                items,
+                err: None,
+                attempts: self.attempts,
+                first_seen: first_seen.unwrap_or_else(SystemTime::now),
+                last_attempt: SystemTime::now(),
+            });
+            return;
+        }
+        self.each_item(self.buffer.iter().take(len), |hooks, item| {
+            hooks.on_requeue(item)
+        });
+    }
+
+    /// Counts the `coalesce_by` keys of items back in the buffer or out of it
+    fn track_keys(&mut self, items: &[T], buffered: bool) {
+        let key_by = match &self.opts.coalesce_by {
+            Some(key_by) => key_by,
+            None => return,
+        };
+        for item in items {
+            let key = key_by(item);
+            let count = self.buffered_keys.entry(key).or_default();
+            if buffered {
+                *count += 1;
+            } else if *count > 1 {
+                *count -= 1;
+            } else {
+                self.buffered_keys.remove(&key);
+            }
+        }
+    }
+
+    /// Runs `validate_batch` over the items of a release past the `carried` ones
+    fn validate_batch(&mut self, items: &mut Vec<T>, carried: usize) {
+        let validate = match &self.opts.validate_batch {
+            Some(validate) => validate,
+            None => return,
+        };
+        let mut own = items.split_off(carried);
+        let rejected = validate(&mut own);
+        items.append(&mut own);
+
+        self.stats.rejected += rejected.len();
+        for rejected in rejected {
+            self.track_keys(std::slice::from_ref(&rejected.item), false);
+            if let Some(cursor) = self.cursor(&rejected.item) {
+                self.cursors.release(cursor);
+            }
+            let undelivered = match &self.opts.rejected {
+                Some(tx) => tx.try_send(rejected).err().map(|err| err.into_inner().item),
+                None => Some(rejected.item),
+            };
+            if let Some(item) = undelivered {
+                self.discard(vec![item], Discarded::Rejected);
+            }
+        }
+        if let (Some(checkpoint), Some(through)) = (&self.opts.checkpoint, self.cursors.advance()) {
+            (checkpoint.on_checkpoint)(through);
+        }
+    }
+
+    /// Keeps items returned after the backoff was exhausted out of further releases
+    fn give_up(&mut self, dead_letter: DeadLetter<T>) {
+        self.stats.exhausted += dead_letter.items.len();
+        self.settle_cursors(&dead_letter.items);
+        self.wake_progress();
+        self.exhausted.push(dead_letter);
+    }
+
+    fn confirm(&mut self, items: &[T]) {
+        self.in_flight = self.in_flight.saturating_sub(items.len());
+        self.resolve(items);
+    }
+
+    /// Bookkeeping of successfully processed items, a success resets the backoff
+    fn resolve(&mut self, items: &[T]) {
+        self.attempts = 0;
+        if let Some(backoff) = &self.backoff {
+            backoff.on_confirm();
+        }
+        self.settle(items);
+    }
+
+    /// Bookkeeping of confirmed items leaving the backoff as it is, for partial confirms
+    fn settle(&mut self, items: &[T]) {
+        self.stats.confirmed += items.len();
+        self.each_item(items, |hooks, item| hooks.on_confirm(item));
+        self.wake_producers();
+        self.wake_consumer();
+        self.wake_progress();
+
+        if let Some(dedup) = &self.opts.dedup {
+            for item in items {
+                self.dedup.remember((dedup.key)(item), dedup.capacity);
+            }
+        }
+
+        self.settle_cursors(items);
+    }
+
+    /// Lets the checkpoint move past items nobody is going to release again, confirmed, dropped
+    /// or given up on
+    fn settle_cursors(&mut self, items: &[T]) {
+        if let Some(checkpoint) = &self.opts.checkpoint {
+            for item in items {
+                self.cursors.release((checkpoint.cursor)(item));
+            }
+            if let Some(through) = self.cursors.advance() {
+                (checkpoint.on_checkpoint)(through);
+            }
+        }
+    }
+
+    fn sample(&mut self) -> bool {
+        let seen = self.seen;
+        self.seen = self.seen.wrapping_add(1);
+        match self.opts.sampling {
+            None => true,
+            Some(Sampling::EveryNth(n)) => seen % n.max(1) == 0,
+            Some(Sampling::Fraction(fraction)) => fastrand::f64() < fraction,
+        }
+    }
+
+    /// Items waiting in the release queues, excluding delayed batches
+    fn queued(&self) -> usize {
+        self.buffer.len() + self.retry.len() + self.panes.as_ref().map_or(0, Panes::len)
+    }
+
+    fn len(&self) -> usize {
+        self.queued()
+            + self.urgent.len()
+            + self.delayed.iter().map(|d| d.items.len()).sum::<usize>()
+    }
+
+    fn retry_order(&self) -> RetryOrder {
+        if self.opts.ordered_retries {
+            RetryOrder::First
+        } else {
+            self.opts.retry_order
+        }
+    }
+
+    /// Buffered items in the order they are going to be released
+    fn iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        let (retry, fresh) = (self.retry.iter(), self.buffer.iter());
+        match self.retry_order() {
+            RetryOrder::First => Box::new(retry.chain(fresh)),
+            RetryOrder::Last => Box::new(fresh.chain(retry)),
+            RetryOrder::Mixed => Box::new(interleave(retry, fresh)),
+        }
+    }
+
+    /// Takes the first `n` items in release order
+    fn take(&mut self, n: usize) -> Vec<T> {
+        let (retry_len, fresh_len) = (self.retry.len(), self.buffer.len());
+        let (retried, fresh) = match self.retry_order() {
+            RetryOrder::First => {
+                let retried = n.min(retry_len);
+                (retried, (n - retried).min(fresh_len))
+            }
+            RetryOrder::Last => {
+                let fresh = n.min(fresh_len);
+                ((n - fresh).min(retry_len), fresh)
+            }
+            RetryOrder::Mixed => {
+                let fresh = (n - retry_len.min((n + 1) / 2)).min(fresh_len);
+                ((n - fresh).min(retry_len), fresh)
+            }
+        };
+        self.take_split(retried, fresh)
+    }
+
+    /// Takes the first `retried` returned and `fresh` fresh items, merged in release order
+    fn take_split(&mut self, retried: usize, fresh: usize) -> Vec<T> {
+        let order = self.retry_order();
+        let retried = self.retry.drain(..retried);
+        let fresh = self.buffer.drain_up_to(fresh);
+        self.arrivals.drain(..fresh.len().min(self.arrivals.len()));
+        let fresh = fresh.into_iter();
+        let items: Vec<T> = match order {
+            RetryOrder::First => retried.chain(fresh).collect(),
+            RetryOrder::Last => fresh.chain(retried).collect(),
+            RetryOrder::Mixed => interleave(retried, fresh).collect(),
+        };
+        self.weight = self.weight.saturating_sub(self.weigh_all(&items));
+        items
+    }
+
+    fn update_group_end(&mut self) {
+        self.group_end = self.opts.group_by.as_ref().and_then(|same_group| {
+            self.iter()
+                .zip(self.iter().skip(1))
+                .position(|(a, b)| !same_group(a, b))
+                .map(|i| i + 1)
+        });
+    }
+
+    fn update_deadline(&mut self) {
+        self.next_deadline = self
+            .opts
+            .deadline
+            .as_ref()
+            .and_then(|deadline| self.iter().map(&deadline.deadline).min());
+    }
+
+    fn deadline_due(&self) -> bool {
+        match (&self.opts.deadline, self.next_deadline) {
+            (Some(deadline), Some(due)) => SystemTime::now() + deadline.margin >= due,
+            _ => false,
+        }
+    }
+
+    /// Counts released items that missed their deadline or only just made it
+    fn count_deadlines(&mut self, items: &[T]) {
+        let deadline = match &self.opts.deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        let now = SystemTime::now();
+        for item in items {
+            let due = (deadline.deadline)(item);
+            if due <= now {
+                self.stats.missed_deadlines += 1;
+            } else if due <= now + deadline.margin {
+                self.stats.near_misses += 1;
+            }
+        }
+    }
+
+    /// Hands the event to every observer with room for it, observers gone are dropped
+    fn emit(&mut self, event: ReleaseEvent) {
+        self.observers.retain(|tx| {
+            !matches!(
+                tx.try_send(event.clone()),
+                Err(TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    /// Gets `next` out of waiting, something may be due now
+    fn wake_consumer(&self) {
+        let _ = self.wake_tx.try_send(());
+    }
+
+    fn wake_producers(&mut self) {
+        for waker in self.space_waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn wake_progress(&mut self) {
+        for waker in self.progress_waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    /// Lengths of the pieces but the last one `items` get split into under `max_payload`
+    fn payload_cuts(&self, items: &[T]) -> Vec<usize> {
+        let max_payload = match self.opts.max_payload {
+            Some(max_payload) => max_payload,
+            None => return vec![],
+        };
+        let mut cuts = vec![];
+        let (mut len, mut weight) = (0, 0);
+        for item in items {
+            let item_weight = self.weigh(item);
+            if len > 0 && weight + item_weight > max_payload {
+                cuts.push(len);
+                len = 0;
+                weight = 0;
+            }
+            len += 1;
+            weight += item_weight;
+        }
+        cuts
+    }
+
+    /// Orders the items by `time_buckets` bucket, keeping their order within a bucket, and gives
+    /// the length and span of every bucket
+    fn bucket_items(&self, items: &mut [T]) -> Vec<(usize, Range<SystemTime>)> {
+        let buckets = match &self.opts.time_buckets {
+            Some(buckets) => buckets,
+            None => return vec![],
+        };
+        let bucket = |item: &T| wall_window((buckets.time)(item), buckets.period);
+        items.sort_by_cached_key(bucket);
+
+        let mut runs: Vec<(u128, usize)> = vec![];
+        for item in items.iter() {
+            let index = bucket(item);
+            match runs.last_mut() {
+                Some((last, len)) if *last == index => *len += 1,
+                _ => runs.push((index, 1)),
+            }
+        }
+        runs.into_iter()
+            .map(|(index, len)| {
+                let span =
+                    window_start(index, buckets.period)..window_start(index + 1, buckets.period);
+                (len, span)
             })
+            .collect()
+    }
+
+    /// Lengths of the pieces but the last one a release gets split into by `time_buckets` and
+    /// `max_payload`, with the bucket span of every piece
+    fn release_cuts(
+        &self,
+        items: &mut [T],
+        windowed: bool,
+    ) -> (Vec<usize>, Vec<Range<SystemTime>>) {
+        let buckets = if windowed {
+            vec![]
+        } else {
+            self.bucket_items(items)
+        };
+        if buckets.is_empty() {
+            return (self.payload_cuts(items), vec![]);
+        }
+
+        let (mut cuts, mut spans) = (vec![], vec![]);
+        let mut start = 0;
+        for (len, span) in buckets {
+            let payload_cuts = self.payload_cuts(&items[start..start + len]);
+            let last = len - payload_cuts.iter().sum::<usize>();
+            for cut in payload_cuts.into_iter().chain(Some(last)) {
+                cuts.push(cut);
+                spans.push(span.clone());
+            }
+            start += len;
+        }
+        cuts.pop();
+        (cuts, spans)
+    }
+
+    fn record_err(&mut self, source: ErrorSource, err: Arc<anyhow::Error>) {
+        if self.errors.len() >= self.opts.error_history {
+            self.errors.pop_front();
+        }
+        if self.opts.error_history > 0 {
+            self.errors.push_back(RecordedError {
+                at: SystemTime::now(),
+                source,
+                err,
+            });
+        }
+    }
+
+    fn set_err(&mut self, err: Terminal) {
+        self.err.get_or_insert(err);
+        self.wake_consumer();
+    }
+
+    fn is_ready(&self, full: bool) -> Option<Reason> {
+        if self.acquired.is_some() {
+            return None;
+        }
+        // once terminated whatever is urgent goes out with the `Reason::Term` release
+        if !self.urgent.is_empty() && self.err.is_none() {
+            return Some(Reason::Urgent);
+        }
+        if self.in_flight_room() == 0 {
+            return None;
+        }
+
+        if self.opts.ordered_retries {
+            // the oldest returned batch goes first and holds everything else back
+            if let Some(delayed) = self.delayed.first() {
+                return delayed.is_due().then_some(Reason::Retry);
+            }
+        } else if self.delayed.iter().any(Delayed::is_due) {
+            return Some(Reason::Retry);
+        }
+
+        if self.queued() == 0 {
+            if self.err.is_some() && self.delayed.is_empty() {
+                return Some(Reason::Term);
+            }
+
+            return (self.opts.heartbeat && self.err.is_none() && self.time_due())
+                .then_some(Reason::Time);
+        }
+        if matches!(&self.backoff, Some(backoff) if backoff.is_waiting())
+            || matches!(self.not_before, Some(not_before) if Instant::now() < not_before)
+        {
+            if full && self.opts.bypass_backoff_when_full {
+                return Some(Reason::Backpressure);
+            }
+            return None;
+        }
+
+        if self.err.is_some() {
+            return Some(Reason::Term);
+        }
+
+        if self.flush_left > 0 {
+            return Some(Reason::Flush);
+        }
+
+        if self.deadline_due() {
+            return Some(Reason::Deadline);
+        }
+
+        if let Some(panes) = &self.panes {
+            return (!self.retry.is_empty() || panes.is_closed()).then_some(Reason::Window);
+        }
+
+        if self.group_end.is_some() {
+            return Some(Reason::Group);
+        }
+
+        if self.queued() >= self.opts.soft_cap {
+            return Some(Reason::Size);
+        }
+
+        if matches!(self.opts.soft_cap_weight, Some(cap) if self.weight >= cap) {
+            return Some(Reason::Weight);
+        }
+
+        if self.over_age() > 0 {
+            return Some(Reason::Age);
+        }
+
+        if self.time_due() {
+            return Some(Reason::Time);
+        }
+
+        None
+    }
+
+    /// Time left until the earliest time-based trigger, `None` when something is due already
+    fn next_release_in(&self) -> Option<Duration> {
+        if self.is_ready(false).is_some() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut next = if self.opts.align_release {
+            window_start(self.aligned + 1, self.opts.release_after)
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        } else {
+            self.opts
+                .release_after
+                .saturating_sub(self.last_ok_consume.elapsed())
+        };
+        if let (Some(max_age), Some(oldest)) = (self.opts.max_age, self.arrivals.front()) {
+            next = next.min(max_age.saturating_sub(oldest.elapsed()));
+        }
+        if let (Some(deadline), Some(due)) = (&self.opts.deadline, self.next_deadline) {
+            let release_at = due.checked_sub(deadline.margin).unwrap_or(UNIX_EPOCH);
+            next = next.min(
+                release_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default(),
+            );
+        }
+        // fresh items wait out a backoff or `return_after`, delayed batches only their own
+        let held = [
+            self.backoff.as_ref().and_then(SharedBackoff::retry_at),
+            self.not_before,
+        ];
+        if let Some(until) = held.iter().flatten().max() {
+            next = next.max(until.saturating_duration_since(now));
+        }
+        let delayed = if self.opts.ordered_retries {
+            &self.delayed[..self.delayed.len().min(1)]
+        } else {
+            &self.delayed[..]
+        };
+        for retry_at in delayed.iter().filter_map(|delayed| delayed.retry_at) {
+            next = next.min(retry_at.saturating_duration_since(now));
+        }
+        Some(next)
+    }
+
+    /// Time left until the earliest timer still ahead(release_after, max_age, deadlines, window
+    /// closes, backoffs and delayed retries), `None` when nothing is scheduled.
+    ///
+    /// Unlike `next_release_in` it skips the triggers that can't fire right now, e.g.
+    /// `release_after` of an empty buffer.
+    fn next_timer(&self) -> Option<Duration> {
+        let now = Instant::now();
+        if self.is_held() {
+            // no timer fires until a resolve lifts the hold, or the held back retry is due
+            let head = self.delayed.first().filter(|_| self.opts.ordered_retries);
+            return head
+                .and_then(|delayed| delayed.retry_at)
+                .map(|retry_at| retry_at.saturating_duration_since(now));
+        }
+        let mut fresh = None;
+        let mut earliest = |next: Duration| {
+            fresh = Some(fresh.map_or(next, |fresh: Duration| fresh.min(next)));
+        };
+        let heartbeat = self.opts.heartbeat && self.err.is_none();
+        if self.panes.is_none() && (self.queued() > 0 || heartbeat) {
+            let next = if self.opts.align_release {
+                window_start(self.aligned + 1, self.opts.release_after)
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+            } else {
+                self.opts
+                    .release_after
+                    .saturating_sub(self.last_ok_consume.elapsed())
+            };
+            earliest(next);
+        }
+        if let (Some(max_age), Some(oldest)) = (self.opts.max_age, self.arrivals.front()) {
+            earliest(max_age.saturating_sub(oldest.elapsed()));
+        }
+        if let (Some(deadline), Some(due)) = (&self.opts.deadline, self.next_deadline) {
+            let release_at = due.checked_sub(deadline.margin).unwrap_or(UNIX_EPOCH);
+            earliest(
+                release_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default(),
+            );
+        }
+        if let Some(closes_in) = self.panes.as_ref().and_then(Panes::closes_in) {
+            earliest(closes_in);
+        }
+
+        let mut next = fresh;
+        let held = [
+            self.backoff.as_ref().and_then(SharedBackoff::retry_at),
+            self.not_before,
+        ];
+        if let Some(until) = held.iter().flatten().max() {
+            next = next.map(|next| next.max(until.saturating_duration_since(now)));
+        }
+        let delayed = if self.opts.ordered_retries {
+            &self.delayed[..self.delayed.len().min(1)]
+        } else {
+            &self.delayed[..]
+        };
+        for retry_at in delayed.iter().filter_map(|delayed| delayed.retry_at) {
+            let retry_in = retry_at.saturating_duration_since(now);
+            next = Some(next.map_or(retry_in, |next| next.min(retry_in)));
+        }
+        next
+    }
+
+    /// Whether releases wait on something other than time, an outstanding `Acquired`, the
+    /// `max_in_flight` room or the oldest returned batch under `ordered_retries`
+    fn is_held(&self) -> bool {
+        let ordered =
+            self.opts.ordered_retries && self.delayed.first().is_some_and(|d| !d.is_due());
+        self.acquired.is_some() || self.urgent.is_empty() && (self.in_flight_room() == 0 || ordered)
+    }
+
+    fn time_due(&self) -> bool {
+        if self.opts.align_release {
+            wall_window(SystemTime::now(), self.opts.release_after) > self.aligned
+        } else {
+            self.last_ok_consume.elapsed() >= self.opts.release_after
+        }
+    }
+
+    /// Fresh items buffered longer than `max_age`, they are the first ones to be released
+    fn over_age(&self) -> usize {
+        match self.opts.max_age {
+            Some(max_age) => self
+                .arrivals
+                .iter()
+                .take_while(|arrived| arrived.elapsed() >= max_age)
+                .count(),
+            None => 0,
+        }
+    }
+
+    fn max_priority(&self, items: &[T]) -> Option<u64> {
+        let priority = self.opts.priority.as_ref()?;
+        items.iter().map(priority).max()
+    }
+
+    /// Moves the turn on once a channel gave its weight of items
+    fn served(&mut self, source: usize) {
+        let weight = match source {
+            0 => 1,
+            lane => self.lanes.get(lane - 1).map_or(1, |lane| lane.weight),
+        };
+        if source != self.turn {
+            self.turn = source;
+            self.served = 0;
+        }
+        self.served += 1;
+        if self.served >= weight {
+            self.turn = (source + 1) % (self.lanes.len() + 1);
+            self.served = 0;
+        }
+    }
+
+    /// How many more items may be released under `max_in_flight`
+    fn in_flight_room(&self) -> usize {
+        self.opts
+            .max_in_flight
+            .map_or(usize::MAX, |max| max.saturating_sub(self.in_flight))
+    }
+
+    fn stats(&self) -> Stats {
+        let uptime = self.observed.since.elapsed().as_secs_f64();
+        Stats {
+            buffered: self.len(),
+            in_flight: self.in_flight,
+            received_per_sec: self.stats.received as f64 / uptime.max(f64::EPSILON),
+            memory: self.memory_usage(),
+            name: self.opts.name.clone(),
+            ..self.stats.clone()
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        let item = std::mem::size_of::<T>();
+        let delayed = self.delayed.iter().flat_map(|delayed| delayed.items.iter());
+        let panes = self.panes.iter().flat_map(Panes::iter);
+        let items = self.iter().chain(delayed).chain(panes);
+        let weight = match &self.opts.weigher {
+            Some(weigh) => items.map(weigh).sum(),
+            None => items.count() * item,
+        };
+        let spare = self.buffer.capacity().saturating_sub(self.buffer.len())
+            + (self.retry.capacity() - self.retry.len());
+        weight + spare * item
+    }
+
+    fn shrink(&mut self) {
+        let shrink_after = match self.opts.shrink_after {
+            Some(shrink_after) => shrink_after,
+            None => return,
+        };
+        let capacity = self.buffer.capacity().max(self.retry.capacity());
+        if capacity <= self.opts.soft_cap || self.queued() >= capacity / 10 {
+            self.sparse_since = None;
+            return;
+        }
+        let since = *self.sparse_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= shrink_after {
+            self.buffer.shrink_to(self.opts.soft_cap);
+            self.retry.shrink_to(self.opts.soft_cap);
+            self.sparse_since = None;
+        }
+    }
+
+    /// Restarts the release timer, returns how long it ran
+    fn restart(&mut self) -> Duration {
+        let elapsed = self.last_ok_consume.elapsed();
+        self.last_ok_consume = Instant::now();
+        self.aligned = wall_window(SystemTime::now(), self.opts.release_after);
+        elapsed
+    }
+
+    /// Returned batch to release with `Reason::Retry`, only the oldest one under
+    /// `ordered_retries`
+    fn due_delayed(&self) -> Option<usize> {
+        match self.opts.ordered_retries {
+            true => self.delayed.first().filter(|d| d.is_due()).map(|_| 0),
+            false => self.delayed.iter().position(Delayed::is_due),
+        }
+    }
+
+    fn consume(&mut self, reason: Reason) -> Consumed<T> {
+        self.wake_progress();
+        // urgent items left when it terminated go out first with `Reason::Term`
+        if reason == Reason::Urgent || reason == Reason::Term && !self.urgent.is_empty() {
+            // not a batch, the time trigger keeps counting from the latest one
+            return Consumed {
+                elapsed: self.last_ok_consume.elapsed(),
+                items: std::mem::take(&mut self.urgent),
+                window: None,
+                backoff: None,
+                attempt: 0,
+                carried: 0,
+                late: false,
+                first_seen: None,
+            };
+        }
+        let elapsed = self.restart();
+        // another consumer may have taken the due batch since `is_ready` saw it
+        let due = match reason {
+            Reason::Retry => self.due_delayed(),
+            _ => None,
+        };
+        // every release but urgent ones fits in what's left of `max_in_flight`
+        let room = self.in_flight_room();
+        if let Some(due) = due {
+            let mut delayed = self.delayed.remove(due);
+            if delayed.items.len() > room {
+                let rest = Delayed {
+                    items: delayed.items.split_off(room),
+                    backoff: delayed.backoff.as_ref().map(clone_backoff),
+                    window: delayed.window.clone(),
+                    ..delayed
+                };
+                self.delayed.insert(due, rest);
+            }
+            let Delayed {
+                items,
+                backoff,
+                attempt,
+                window,
+                first_seen,
+                ..
+            } = delayed;
+            return Consumed {
+                elapsed,
+                items,
+                window,
+                backoff,
+                attempt,
+                carried: 0,
+                late: false,
+                first_seen: Some(first_seen),
+            };
+        }
+
+        let pane = match &mut self.panes {
+            Some(panes) if self.retry.is_empty() => panes.pop(),
+            _ => None,
+        };
+        if let Some(mut pane) = pane {
+            let own = self.weigh_all(&pane.items[pane.carried..]);
+            self.weight = self.weight.saturating_sub(own);
+            // the rest of the window goes out next from the front of the retry queue
+            let keep = pane.carried.saturating_add(room);
+            if pane.items.len() > keep {
+                let rest = pane.items.split_off(keep);
+                self.weight += self.weigh_all(&rest);
+                for item in rest.into_iter().rev() {
+                    self.retry.push_front(item);
+                }
+                self.retry_window = Some(pane.span.clone());
+            }
+            return Consumed {
+                elapsed,
+                items: pane.items,
+                window: Some(pane.span),
+                backoff: None,
+                attempt: 0,
+                carried: pane.carried,
+                late: pane.late,
+                first_seen: None,
+            };
+        }
+        let window = self.retry_window.take();
+        let first_seen = self.retry_since.take();
+
+        let mut items = if reason == Reason::Age {
+            let retried = self.retry.len().min(room);
+            let fresh = self.over_age().min(room - retried);
+            self.take_split(retried, fresh)
+        } else {
+            let end = self.group_end.unwrap_or_else(|| self.queued());
+            self.take(end.min(room))
+        };
+        if let Some(sort_by) = &self.opts.sort_by {
+            items.sort_by(|a, b| sort_by(a, b));
+        }
+        if let (Some(priority), true) = (&self.opts.priority, self.opts.sort_by_priority) {
+            items.sort_by_key(|item| std::cmp::Reverse(priority(item)));
+        }
+        self.update_group_end();
+        self.update_deadline();
+        Consumed {
+            elapsed,
+            items,
+            window,
+            backoff: None,
+            attempt: 0,
+            carried: 0,
+            late: false,
+            first_seen,
+        }
+    }
+}
+
+/// Alternates between two iterators, what's left of the longer one comes last
+fn interleave<A: Iterator, B: Iterator<Item = A::Item>>(
+    a: A,
+    b: B,
+) -> impl Iterator<Item = A::Item> {
+    let (mut a, mut b) = (a.fuse(), b.fuse());
+    let mut a_turn = false;
+    std::iter::from_fn(move || {
+        a_turn = !a_turn;
+        if a_turn {
+            a.next().or_else(|| b.next())
+        } else {
+            b.next().or_else(|| a.next())
+        }
+    })
+}
+
+pub struct RelaBuf<T> {
+    /// Shared so a `Registry` can reach it weakly
+    rx_buffer: Arc<Receiver<T>>,
+    state: Arc<Mutex<State<T>>>,
+    /// Rest of a release split by `max_payload`
+    pending: Arc<Mutex<VecDeque<Released<T>>>>,
+}
+
+/// Hands whatever is still buffered or in the channel to `on_drop` and `item_hooks` with
+/// `Discarded::Undelivered`, so dropping a buffer early doesn't lose items silently
+impl<T> Drop for RelaBuf<T> {
+    fn drop(&mut self) {
+        // dropped after the state is unlocked
+        let mut pieces: Vec<_> = self.pending.lock().unwrap().drain(..).collect();
+        let mut s = self.state.lock().unwrap();
+        if s.opts.on_drop.is_none() && s.opts.item_hooks.is_none() {
+            return;
+        }
+
+        let mut items = s.take_undelivered();
+        for piece in &mut pieces {
+            let split: Vec<_> = piece.items.drain(piece.carried..).collect();
+            s.in_flight = s.in_flight.saturating_sub(split.len());
+            piece.resolved.store(true, atomic::Ordering::Relaxed);
+            items.extend(split);
+        }
+        items.extend(self.rx_buffer.drain());
+        for lane in &s.lanes {
+            items.extend(lane.rx.drain());
+        }
+        if !items.is_empty() {
+            s.stats.undelivered += items.len();
+            s.discard(items, Discarded::Undelivered);
+        }
+    }
+}
+
+/// Wakes `next` up before a send blocks on a full intake, `bypass_backoff_when_full` releases
+/// on it while nothing is taken in
+fn wake_if_full<T>(tx_buffer: &Sender<T>, wake_tx: &Sender<()>) {
+    if tx_buffer.is_full() {
+        let _ = wake_tx.try_send(());
+    }
+}
+
+/// Takes items in until the buffer has something to release
+async fn wait_ready<T>(state: &Mutex<State<T>>, rx_buffer: &Receiver<T>) -> Reason {
+    loop {
+        let ready = {
+            let mut s = state.lock().unwrap();
+            s.shrink();
+            let full = rx_buffer.is_full() || s.lanes.iter().any(|lane| lane.rx.is_full());
+            s.is_ready(full)
+        };
+        if let Some(reason) = ready {
+            return reason;
+        }
+
+        let (timeout_dur, wake_rx) = {
+            let s = state.lock().unwrap();
+            // a timer seemingly due while nothing is ready mustn't spin
+            let wait = s
+                .next_timer()
+                .map(|next| next.max(Duration::from_millis(1)));
+            // held back releases wait for the resolve waking them up instead of polling
+            let wait = match s.is_held() {
+                true => wait,
+                false => Some(wait.unwrap_or(s.opts.poll_interval)),
+            };
+            (wait, s.wake_rx.clone())
+        };
+        let woken = async {
+            let _ = wake_rx.recv_async().await;
+            None
+        };
+        if state.lock().unwrap().can_receive() {
+            let received = async { Some(recv_fair(state, rx_buffer).await) };
+            if let Some(Some(r)) = within(future::or(woken, received), timeout_dur).await {
+                match r {
+                    Ok(item) => {
+                        let (diverted, pace) = {
+                            let mut s = state.lock().unwrap();
+                            s.wake_producers();
+                            let pace = s.opts.intake_limiter.as_ref().map(|limit| limit());
+                            (s.intake(item), pace)
+                        };
+                        if let Some(diverted) = diverted {
+                            diverted.send().await;
+                        }
+                        if let Some(pace) = pace {
+                            pace.await;
+                        }
+                    }
+                    Err(_) => {
+                        let mut s = state.lock().unwrap();
+                        let err = match s.producer_err.take() {
+                            Some(err) => Terminal::ProducerError(err),
+                            None => Terminal::ProducerClosed,
+                        };
+                        s.set_err(err)
+                    }
+                }
+            }
+        } else {
+            within(woken, timeout_dur).await;
+        }
+    }
+}
+
+/// Awaits `fut` for at most `timeout`, for as long as it takes without one
+async fn within<F: Future>(fut: F, timeout: Option<Duration>) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => fut.timeout(timeout).await,
+        None => Some(fut.await),
+    }
+}
+
+/// See `RelaBuf::close_with_timeout`
+async fn close<T>(
+    state: &Mutex<State<T>>,
+    rx_buffer: &Receiver<T>,
+    timeout: Duration,
+) -> ShutdownReport {
+    let confirmed = {
+        let mut s = state.lock().unwrap();
+        s.set_err(Terminal::ConsumerTerminated);
+        s.stats.confirmed
+    };
+
+    let drained = until(state, |s| s.len() + s.in_flight == 0);
+    drained.timeout(timeout).await;
+    let s = state.lock().unwrap();
+    let in_lanes: usize = s.lanes.iter().map(|lane| lane.rx.len()).sum();
+    ShutdownReport {
+        received: s.stats.received,
+        released: s.observed.released,
+        confirmed: s.stats.confirmed,
+        retried: s.stats.returned,
+        dead_lettered: s.stats.exhausted,
+        expired: s.stats.too_late,
+        delivered: s.stats.confirmed - confirmed,
+        stranded: s.len() + s.in_flight + rx_buffer.len() + in_lanes,
+    }
+}
+
+/// See `RelaBuf::flush`
+async fn flush<T>(state: Arc<Mutex<State<T>>>) {
+    {
+        let mut s = state.lock().unwrap();
+        s.flush_left = s.queued();
+        s.wake_consumer();
+    }
+    until(&state, |s| {
+        let flushed = s.flush_left == 0 || s.queued() == 0;
+        if flushed {
+            s.flush_left = 0;
+        }
+        flushed
+    })
+    .await
+}
+
+/// Resolves once `done` holds, checked again whenever items are released or resolved
+async fn until<T>(state: &Mutex<State<T>>, mut done: impl FnMut(&mut State<T>) -> bool) {
+    future::poll_fn(|cx| {
+        let mut s = state.lock().unwrap();
+        if done(&mut s) {
+            return Poll::Ready(());
+        }
+        if !s.progress_waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            s.progress_waiters.push(cx.waker().clone());
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Buffer listed in a `Registry`, gone once the `RelaBuf` is
+struct Registration<T> {
+    state: Weak<Mutex<State<T>>>,
+    /// Held by the `RelaBuf` alone, producers and releases keep `state` alive past it
+    rx_buffer: Weak<Receiver<T>>,
+}
+
+impl<T> Registration<T> {
+    fn state(&self) -> Option<Arc<Mutex<State<T>>>> {
+        self.rx_buffer.upgrade()?;
+        self.state.upgrade()
+    }
+}
+
+impl<T: 'static + Send> Registered for Registration<T> {
+    fn stats(&self) -> Option<Stats> {
+        let state = self.state()?;
+        let stats = state.lock().unwrap().stats();
+        Some(stats)
+    }
+
+    fn flush(&self) -> Option<PinnedFut<'static>> {
+        let state = self.state()?;
+        Some(Box::pin(flush(state)))
+    }
+
+    fn close(&self, timeout: Duration) -> Option<PinnedFut<'static, ShutdownReport>> {
+        let (state, rx_buffer) = (self.state.upgrade()?, self.rx_buffer.upgrade()?);
+        Some(Box::pin(
+            async move { close(&state, &rx_buffer, timeout).await },
+        ))
+    }
+}
+
+/// Receives from the main channel and every lane, starting with the one whose turn it is, fails
+/// once all of them are disconnected
+async fn recv_fair<T>(
+    state: &Mutex<State<T>>,
+    rx_buffer: &Receiver<T>,
+) -> std::result::Result<T, flume::RecvError> {
+    let (lanes, turn) = {
+        let mut s = state.lock().unwrap();
+        s.lanes
+            .retain(|lane| !(lane.rx.is_disconnected() && lane.rx.is_empty()));
+        let lanes: Vec<_> = s.lanes.iter().map(|lane| lane.rx.clone()).collect();
+        (lanes, s.turn)
+    };
+    if lanes.is_empty() {
+        return rx_buffer.recv_async().await;
+    }
+
+    let sources: Vec<_> = std::iter::once(rx_buffer).chain(&lanes).collect();
+    let mut recvs: Vec<_> = sources.iter().map(|rx| rx.recv_async()).collect();
+    let (source, item) = future::poll_fn(|cx| {
+        let mut open = false;
+        for k in 0..sources.len() {
+            let i = (turn + k) % sources.len();
+            if sources[i].is_disconnected() && sources[i].is_empty() {
+                continue;
+            }
+            open = true;
+            if let Poll::Ready(Ok(item)) = Pin::new(&mut recvs[i]).poll(cx) {
+                return Poll::Ready(Ok((i, item)));
+            }
+        }
+        if open {
+            Poll::Pending
+        } else {
+            Poll::Ready(Err(flume::RecvError::Disconnected))
+        }
+    })
+    .await?;
+    state.lock().unwrap().served(source);
+    Ok(item)
+}
+
+pub struct RelaBufProxy<T, F> {
+    tx_buffer: Sender<T>,
+    wake_tx: Sender<()>,
+    recv: F,
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<
+        'a,
+        T: 'static + Send + Sync + std::fmt::Debug,
+        F: 'static + Send + Fn() -> PinnedFut<'a, Result<T>>,
+    > RelaBufProxy<T, F>
+{
+    pub async fn go(self) {
+        if let Err(err) = self.go_until_err().await {
+            self.state.lock().unwrap().producer_err = Some(err);
+        }
+    }
+
+    /// Forwards items like `go` but hands the source's error back instead of terminating the
+    /// buffer, so a source can be swapped for a reconnected one with `set_source` without losing
+    /// what's buffered. Returns `Ok` once the buffer is gone.
+    pub async fn go_until_err(&self) -> std::result::Result<(), Arc<anyhow::Error>> {
+        self.forward(&mut false).await
+    }
+
+    /// Sets `forwarded` once an item made it into the buffer
+    async fn forward(&self, forwarded: &mut bool) -> std::result::Result<(), Arc<anyhow::Error>> {
+        while !self.tx_buffer.is_disconnected() {
+            match (self.recv)().await {
+                Ok(item) => {
+                    wake_if_full(&self.tx_buffer, &self.wake_tx);
+                    if self.tx_buffer.send_async(item).await.is_err() {
+                        break;
+                    }
+                    *forwarded = true;
+                }
+                Err(err) => {
+                    let err = Arc::new(err);
+                    let mut state = self.state.lock().unwrap();
+                    state.record_err(ErrorSource::Producer, Arc::clone(&err));
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, F> RelaBufProxy<T, F> {
+    /// Feeds the buffer from another source from now on, e.g. after `go_until_err` returned
+    pub fn set_source<G>(self, recv: G) -> RelaBufProxy<T, G> {
+        RelaBufProxy {
+            tx_buffer: self.tx_buffer,
+            wake_tx: self.wake_tx,
+            recv,
+            state: self.state,
+        }
+    }
+}
+
+/// Drains a `Stream` into a `RelaBuf`, the buffer terminates once the stream ends
+pub struct RelaBufStreamProxy<T, S> {
+    tx_buffer: Sender<T>,
+    wake_tx: Sender<()>,
+    stream: S,
+}
+
+impl<T, S: Stream<Item = T>> RelaBufStreamProxy<T, S> {
+    pub async fn go(self) {
+        let tx_buffer = self.tx_buffer;
+        let stream = self.stream;
+        futures_lite::pin!(stream);
+        while let Some(item) = stream.next().await {
+            wake_if_full(&tx_buffer, &self.wake_tx);
+            if tx_buffer.send_async(item).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Feeds a `RelaBuf` from sources made by `connect`, reconnecting with a backoff of its own
+/// whenever the current source or the connection attempt fails
+pub struct Reconnecting<T, C> {
+    tx_buffer: Sender<T>,
+    wake_tx: Sender<()>,
+    connect: C,
+    backoff: ExponentialBackoff,
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<'a, T, C, S> Reconnecting<T, C>
+where
+    T: 'static + Send + Sync + std::fmt::Debug,
+    C: 'static + Send + Fn() -> PinnedFut<'a, Result<S>>,
+    S: 'static + Send + Fn() -> PinnedFut<'a, Result<T>>,
+{
+    /// Runs until the buffer is gone, or terminates it with the last error once the backoff is
+    /// exhausted. The backoff starts over whenever a source delivered an item.
+    pub async fn go(self) {
+        let mut backoff = self.backoff.build();
+        while !self.tx_buffer.is_disconnected() {
+            let err = match (self.connect)().await {
+                Ok(recv) => {
+                    let proxy = RelaBufProxy {
+                        tx_buffer: self.tx_buffer.clone(),
+                        wake_tx: self.wake_tx.clone(),
+                        recv,
+                        state: Arc::clone(&self.state),
+                    };
+                    let mut forwarded = false;
+                    let result = proxy.forward(&mut forwarded).await;
+                    if forwarded {
+                        backoff.reset();
+                    }
+                    match result {
+                        Ok(()) => return,
+                        Err(err) => err,
+                    }
+                }
+                Err(err) => {
+                    let err = Arc::new(err);
+                    let mut state = self.state.lock().unwrap();
+                    state.record_err(ErrorSource::Producer, Arc::clone(&err));
+                    err
+                }
+            };
+            match backoff.next_backoff() {
+                Some(delay) => {
+                    Timer::after(delay).await;
+                }
+                None => {
+                    self.state.lock().unwrap().producer_err = Some(err);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Pushes items straight into a `RelaBuf`, the buffer terminates once every producer is dropped
+pub struct RelaBufProducer<T> {
+    tx_buffer: Sender<T>,
+    wake_tx: Sender<()>,
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Clone for RelaBufProducer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx_buffer: self.tx_buffer.clone(),
+            wake_tx: self.wake_tx.clone(),
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl<T> RelaBufProducer<T> {
+    pub async fn send(&self, item: T) -> Result<()> {
+        if self.is_full() {
+            let full = anyhow::Error::from(BufferFull);
+            return Err(match &self.state.lock().unwrap().opts.name {
+                // still downcasts to `BufferFull`
+                Some(name) => full.context(format!("buffer {} is full", name)),
+                None => full,
+            });
+        }
+        wake_if_full(&self.tx_buffer, &self.wake_tx);
+        self.tx_buffer
+            .send_async(item)
+            .await
+            .map_err(|_| self.gone())
+    }
+
+    fn gone(&self) -> anyhow::Error {
+        match &self.state.lock().unwrap().opts.name {
+            Some(name) => anyhow!("buffer {} is gone", name),
+            None => anyhow!("buffer is gone"),
+        }
+    }
+
+    /// Delivers the item with the very next release, on its own with `Reason::Urgent` and right
+    /// away if the consumer is waiting, for rare control messages that can't wait for a batch.
+    ///
+    /// It skips the intake checks, the caps, backoffs and `max_in_flight`, and fails with the
+    /// `Terminal` once the buffer terminated.
+    pub fn send_urgent(&self, item: T) -> Result<()> {
+        if self.tx_buffer.is_disconnected() {
+            return Err(self.gone());
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = &state.err {
+            return Err(err.clone().into());
+        }
+        state.stats.received += 1;
+        if let Some(hooks) = &state.opts.item_hooks {
+            hooks.on_enqueue(&item);
+        }
+        // counted like any buffered item as releasing it uncounts its key
+        state.track_keys(std::slice::from_ref(&item), true);
+        state.urgent.push(item);
+        state.wake_consumer();
+        Ok(())
+    }
+
+    /// How many items can be sent right now without waiting(or failing under `strict_hard_cap`)
+    pub fn capacity(&self) -> usize {
+        self.room(&self.state.lock().unwrap())
+    }
+
+    /// Ready once there is room for at least one item or the buffer is gone, so upstream can
+    /// pause generating instead of blocking inside `send`
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if self.tx_buffer.is_disconnected() || self.room(&state) > 0 {
+            return Poll::Ready(());
+        }
+        if !state.space_waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            state.space_waiters.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    pub async fn on_space_available(&self) {
+        future::poll_fn(|cx| self.poll_ready(cx)).await
+    }
+
+    /// Items sent but not taken in by the buffer yet
+    pub fn in_channel(&self) -> usize {
+        self.tx_buffer.len()
+    }
+
+    /// Items held by the buffer, returned ones waiting for a retry included
+    pub fn buffered(&self) -> usize {
+        self.state.lock().unwrap().len()
+    }
+
+    /// Channel and buffer occupancy relative to `hard_cap`, for modulating the emit rate
+    pub fn fill_ratio(&self) -> f64 {
+        let hard_cap = self.state.lock().unwrap().opts.hard_cap.max(1);
+        (self.in_channel() + self.buffered()) as f64 / hard_cap as f64
+    }
+
+    fn room(&self, state: &State<T>) -> usize {
+        let queued = self.tx_buffer.len();
+        let free = self.tx_buffer.capacity().unwrap_or(usize::MAX) - queued;
+        if !state.opts.strict_hard_cap {
+            return free;
+        }
+        let occupied = state.len() + state.in_flight + queued;
+        free.min(state.opts.hard_cap.saturating_sub(occupied))
+    }
+
+    fn is_full(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.opts.strict_hard_cap && self.room(&state) == 0
+    }
+
+    /// A producer with a channel of its own, the buffer takes up to `weight` items at a time from
+    /// every channel in turn so a firehose producer can't starve a quiet one
+    pub fn lane(&self, weight: usize) -> RelaBufProducer<T> {
+        let mut state = self.state.lock().unwrap();
+        let (tx_buffer, rx) = bounded(state.opts.hard_cap);
+        state.lanes.push(Lane {
+            rx,
+            weight: weight.max(1),
+        });
+        // a waiting `next` only takes from the channels it knew of
+        state.wake_consumer();
+        RelaBufProducer {
+            tx_buffer,
+            wake_tx: self.wake_tx.clone(),
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl<'a, T: 'static + Send + Sync + std::fmt::Debug> RelaBuf<T> {
+    pub fn new<F: 'static + Send + Fn() -> PinnedFut<'a, Result<T>>>(
+        opts: RelaBufConfig<T>,
+        recv: F,
+    ) -> (Self, RelaBufProxy<T, F>) {
+        let (buf, producer) = Self::with_producer(opts);
+
+        (
+            buf,
+            RelaBufProxy {
+                tx_buffer: producer.tx_buffer,
+                wake_tx: producer.wake_tx,
+                recv,
+                state: producer.state,
+            },
+        )
+    }
+
+    /// Buffer fed from a `Stream`(e.g. a `flume::Receiver::into_stream` or a `tokio_stream`),
+    /// the end of the stream terminates it with `Reason::Term` like dropping every producer
+    pub fn from_stream<S: 'static + Send + Stream<Item = T>>(
+        opts: RelaBufConfig<T>,
+        stream: S,
+    ) -> (Self, RelaBufStreamProxy<T, S>) {
+        let (buf, producer) = Self::with_producer(opts);
+        let proxy = RelaBufStreamProxy {
+            tx_buffer: producer.tx_buffer,
+            wake_tx: producer.wake_tx,
+            stream,
+        };
+        (buf, proxy)
+    }
+
+    /// Buffer fed from sources `connect` makes, e.g. broker subscriptions. When one fails
+    /// `connect` is called again after waiting out `backoff`, what's buffered stays.
+    pub fn reconnecting<C, S>(
+        opts: RelaBufConfig<T>,
+        backoff: ExponentialBackoff,
+        connect: C,
+    ) -> (Self, Reconnecting<T, C>)
+    where
+        C: 'static + Send + Fn() -> PinnedFut<'a, Result<S>>,
+        S: 'static + Send + Fn() -> PinnedFut<'a, Result<T>>,
+    {
+        let (buf, producer) = Self::with_producer(opts);
+
+        (
+            buf,
+            Reconnecting {
+                tx_buffer: producer.tx_buffer,
+                wake_tx: producer.wake_tx,
+                connect,
+                backoff,
+                state: producer.state,
+            },
+        )
+    }
+
+    /// Lists the buffer in `registry` for as long as it's alive
+    pub fn register(&self, registry: &Registry) {
+        registry.add(Box::new(Registration {
+            state: Arc::downgrade(&self.state),
+            rx_buffer: Arc::downgrade(&self.rx_buffer),
+        }));
+    }
+
+    /// Buffer fed from a `std::sync::mpsc` channel, so threaded code can produce without being
+    /// rewritten as async. A bridge thread forwards the items and stops once the channel is
+    /// disconnected(the buffer then terminates with `Terminal::ProducerClosed`) or, within
+    /// `poll_interval`, once the buffer is gone.
+    pub fn from_std_receiver(
+        opts: RelaBufConfig<T>,
+        rx: std::sync::mpsc::Receiver<T>,
+    ) -> std::io::Result<Self> {
+        let poll_interval = opts.poll_interval;
+        let (buf, producer) = Self::with_producer(opts);
+        let alive = producer.tx_buffer.clone();
+        let items = std::iter::from_fn(move || loop {
+            match rx.recv_timeout(poll_interval) {
+                Ok(item) => return Some(item),
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) if !alive.is_disconnected() => {}
+                Err(_) => return None,
+            }
+        });
+        Self::bridge(buf, producer, items)
+    }
+
+    /// Buffer fed from any blocking iterator on a bridge thread like `from_std_receiver`, e.g. a
+    /// `crossbeam_channel::Receiver` or a worker pool's result channel. Once the buffer is gone
+    /// the bridge stops at the next item the iterator gives, or when it ends.
+    pub fn from_blocking_iter<I>(opts: RelaBufConfig<T>, items: I) -> std::io::Result<Self>
+    where
+        I: 'static + Send + IntoIterator<Item = T>,
+    {
+        let (buf, producer) = Self::with_producer(opts);
+        Self::bridge(buf, producer, items)
+    }
+
+    fn bridge<I>(buf: Self, producer: RelaBufProducer<T>, items: I) -> std::io::Result<Self>
+    where
+        I: 'static + Send + IntoIterator<Item = T>,
+    {
+        let RelaBufProducer {
+            tx_buffer, wake_tx, ..
+        } = producer;
+        std::thread::Builder::new()
+            .name("relabuf-bridge".into())
+            .spawn(move || {
+                for item in items {
+                    wake_if_full(&tx_buffer, &wake_tx);
+                    if tx_buffer.send(item).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(buf)
+    }
+
+    /// Feeds transformed releases into another buffer until this one terminates.
+    ///
+    /// Each release is confirmed only after all of its transformed items were accepted downstream,
+    /// if the downstream buffer is gone the release is returned and the error is reported.
+    pub async fn pipe_into<U, M: Fn(&[T]) -> Vec<U>>(
+        &self,
+        other: RelaBufProducer<U>,
+        transform: M,
+    ) -> Result<()> {
+        while let Ok(released) = self.next().await {
+            for item in transform(&released.items) {
+                if let Err(err) = other.send(item).await {
+                    released.return_on_err();
+                    return Err(err);
+                }
+            }
+            released.confirm();
+        }
+        Ok(())
+    }
+
+    /// Releases like `next` and lets `process` work on the items in place, they are confirmed when
+    /// it succeeds and returned with its error otherwise, changes made to them carry over to the
+    /// retry.
+    ///
+    /// e.g. `buf.process_next(|items| Box::pin(async move { upload(items).await })).await`
+    pub async fn process_next<P>(&self, process: P) -> Result<(), Terminal>
+    where
+        P: for<'i> FnOnce(&'i mut [T]) -> PinnedFut<'i, Result<()>>,
+    {
+        let mut released = self.next().await?;
+        match process(&mut released.items).await {
+            Ok(()) => {
+                // a failed `on_confirm` already returned it
+                let _ = released.confirm_async().await;
+            }
+            Err(err) => released.return_with_err(err),
+        }
+        Ok(())
+    }
+
+    /// Drives the buffer until it terminates, handing every release to `handler`.
+    ///
+    /// Up to `concurrency` releases are processed at once, each is confirmed when its handler
+    /// and the `on_confirm` hook succeed and returned to the buffer when either fails.
+    pub async fn run<H: Fn(&[T]) -> PinnedFut<'static, Result<()>>>(
+        &self,
+        concurrency: usize,
+        handler: H,
+    ) {
+        let concurrency = concurrency.max(1);
+        let mut in_flight: Vec<(Released<T>, PinnedFut<'static, Result<()>>, bool)> = vec![];
+        let mut next = Some(self.next());
+
+        future::poll_fn(|cx| loop {
+            let mut i = 0;
+            while i < in_flight.len() {
+                let (released, processing, confirming) = &mut in_flight[i];
+                match processing.as_mut().poll(cx) {
+                    Poll::Ready(Ok(())) if !*confirming => {
+                        if let Some(hook) = released.on_confirm() {
+                            *processing = hook;
+                            *confirming = true;
+                            continue;
+                        }
+                        in_flight.swap_remove(i).0.confirm();
+                    }
+                    Poll::Ready(result) => in_flight.swap_remove(i).0.resolve(result),
+                    Poll::Pending => i += 1,
+                }
+            }
+
+            if in_flight.len() < concurrency {
+                if let Some(Poll::Ready(r)) = next.as_mut().map(|next| next.as_mut().poll(cx)) {
+                    next = match r {
+                        Ok(released) => {
+                            let processing = handler(&released.items);
+                            in_flight.push((released, processing, false));
+                            Some(self.next())
+                        }
+                        Err(_) => None,
+                    };
+                    continue;
+                }
+            }
+
+            if next.is_none() && in_flight.is_empty() {
+                return Poll::Ready(());
+            }
+            return Poll::Pending;
+        })
+        .await
+    }
+
+    /// Estimated time until the next release is due, so a scheduler can sleep exactly that long,
+    /// `None` when one is due already.
+    ///
+    /// It follows `release_after`, `max_age`, backoffs and delayed retries, a size trigger can
+    /// still fire earlier as items arrive.
+    pub fn next_release_in(&self) -> Option<Duration> {
+        if !self.pending.lock().unwrap().is_empty() {
+            return None;
+        }
+        self.state.lock().unwrap().next_release_in()
+    }
+
+    /// Estimated bytes held by the buffer: every buffered item weighs what `weigher` says(its
+    /// inline size without one) and spare capacity counts with the inline size
+    pub fn memory_usage(&self) -> usize {
+        self.state.lock().unwrap().memory_usage()
+    }
+
+    /// Caps and `release_after` fitted to what was observed so far, everything else is left at
+    /// its default.
+    ///
+    /// Releases are spaced about twice the average confirm latency apart, so a consumer keeps up,
+    /// and `soft_cap` is what arrives at the observed rate in between, never below the average
+    /// release. Without observations the current values are kept.
+    pub fn suggest_config(&self) -> RelaBufConfig<T> {
+        let s = self.state.lock().unwrap();
+        let observed = &s.observed;
+        let release_after = observed
+            .confirm_latency()
+            .map_or(s.opts.release_after, |latency| {
+                (latency * 2).max(Duration::from_millis(100))
+            });
+        let soft_cap = match s.stats.received {
+            0 => s.opts.soft_cap,
+            received => {
+                let rate = received as f64 / observed.since.elapsed().as_secs_f64();
+                let arriving = (rate * release_after.as_secs_f64()).ceil() as usize;
+                let average = observed.released / observed.releases.max(1);
+                arriving.max(average).max(1)
+            }
+        };
+        RelaBufConfig {
+            release_after,
+            soft_cap,
+            hard_cap: soft_cap * 2,
+            ..RelaBufConfig::default()
+        }
+    }
+
+    /// Items given up on once the backoff was exhausted, they are never released again
+    pub fn take_exhausted(&self) -> Vec<DeadLetter<T>> {
+        std::mem::take(&mut self.state.lock().unwrap().exhausted)
+    }
+
+    /// Terminates and waits up to `timeout` for everything buffered to be released and resolved
+    /// by whatever keeps calling `next`(e.g. `run` on another task), so shutdowns take a bounded
+    /// time. Items sent but not taken in yet are stranded as intake stops right away.
+    pub async fn close_with_timeout(&self, timeout: Duration) -> ShutdownReport {
+        close(&self.state, &self.rx_buffer, timeout).await
+    }
+
+    /// Releases everything buffered right now with `Reason::Flush`, regardless of `soft_cap` and
+    /// `release_after`, and resolves once all of it was released. Returned items retried later
+    /// don't hold it up, a backoff does.
+    pub fn flush(&self) -> PinnedFut<'static> {
+        Box::pin(flush(Arc::clone(&self.state)))
+    }
+
+    /// Waits for a release like `next` but leaves the items in the buffer until the `Acquired`
+    /// is committed, aborting doesn't move them at all.
+    ///
+    /// Returned items are moved to the front of the buffer to be acquired, `sort_by` or
+    /// `validate_batch` don't apply as the items stay in place. `window` panes are left to `next`,
+    /// with `window` set it fails right away with `Terminal::Failed`.
+    pub fn acquire(&self) -> PinnedFut<'static, Result<Acquired<T>, Terminal>> {
+        let state = Arc::clone(&self.state);
+        let rx_buffer = self.rx_buffer.clone();
+
+        Box::pin(async move {
+            if state.lock().unwrap().panes.is_some() {
+                let err = anyhow!("window panes can't be acquired, only released by next");
+                return Err(Terminal::Failed(Arc::new(err)));
+            }
+            let reason = wait_ready(&state, &rx_buffer).await;
+
+            let mut s = state.lock().unwrap();
+            let elapsed = s.restart();
+            let len = s.acquire(reason);
+            s.observed.released(len);
+            if reason == Reason::Term && len == 0 {
+                s.acquired = None;
+                return Err(s.err.clone().unwrap());
+            }
+            let acquired = Acquired {
+                id: s.next_id(),
+                reason,
+                elapsed,
+                len,
+                state: Arc::clone(&state),
+                acquired_at: Instant::now(),
+                resolved: false,
+            };
+            let event = acquired.event(EventKind::Released, &s);
+            s.emit(event);
+            Ok(acquired)
         })
     }
+
+    pub fn next(&self) -> PinnedFut<'static, Result<Released<T>, Terminal>> {
+        Box::pin(self.release_when_ready())
+    }
+
+    /// Resolves once a release is due without taking it, e.g. to connect downstream before calling
+    /// `next` so items aren't held while connecting. Items keep being taken in until then.
+    ///
+    /// `Reason::Term` also means `next` may return the `Terminal` as nothing is left.
+    pub fn ready(&self) -> PinnedFut<'static, Reason> {
+        Box::pin(self.when_ready())
+    }
+}
+
+impl<T> RelaBuf<T> {
+    /// `next` unboxed, the future is `Send` whenever the items are
+    fn release_when_ready(&self) -> impl Future<Output = Result<Released<T>, Terminal>> {
+        let state = Arc::clone(&self.state);
+        let rx_buffer = self.rx_buffer.clone();
+        let pending = Arc::clone(&self.pending);
+
+        async move {
+            // taken once polled, a `next` dropped unpolled leaves the piece for the next one
+            let split = pending.lock().unwrap().pop_front();
+            if let Some(released) = split {
+                return Ok(released);
+            }
+            let reason = wait_ready(&state, &rx_buffer).await;
+            release(&state, &pending, reason)
+        }
+    }
+
+    fn when_ready(&self) -> impl Future<Output = Reason> {
+        let split = self
+            .pending
+            .lock()
+            .unwrap()
+            .front()
+            .map(|released| released.reason);
+        let state = Arc::clone(&self.state);
+        let rx_buffer = self.rx_buffer.clone();
+
+        async move {
+            match split {
+                Some(reason) => reason,
+                None => wait_ready(&state, &rx_buffer).await,
+            }
+        }
+    }
+
+    pub fn with_producer(mut opts: RelaBufConfig<T>) -> (Self, RelaBufProducer<T>) {
+        let (tx_buffer, rx_buffer) = bounded::<T>(opts.hard_cap);
+
+        let buffer = opts
+            .storage
+            .take()
+            .map_or_else(|| Buffer::Vec(vec![]), Buffer::Custom);
+        let state = Arc::new(Mutex::new(State::new(opts, buffer)));
+
+        let producer = RelaBufProducer {
+            tx_buffer,
+            wake_tx: state.lock().unwrap().wake_tx.clone(),
+            state: Arc::clone(&state),
+        };
+        let buf = Self {
+            rx_buffer: Arc::new(rx_buffer),
+            state,
+            pending: Arc::new(Mutex::new(VecDeque::new())),
+        };
+        (buf, producer)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.state.lock().unwrap().stats()
+    }
+
+    /// Most recent producer and consumer errors, oldest first
+    pub fn errors(&self) -> Vec<RecordedError> {
+        self.state.lock().unwrap().errors.iter().cloned().collect()
+    }
+
+    /// Events of every release handed out, confirmed or returned from now on, for observers like
+    /// audit loggers that must not interfere with the consumer. An observer falling more than
+    /// `capacity`(at least one) events behind misses the newer ones instead of holding the buffer
+    /// up.
+    pub fn subscribe_events(&self, capacity: usize) -> Receiver<ReleaseEvent> {
+        // a rendezvous channel would refuse every event as nobody waits on it when it's sent
+        let (tx, rx) = bounded(capacity.max(1));
+        self.state.lock().unwrap().observers.push(tx);
+        rx
+    }
+
+    /// Changes the backoff of a live buffer without losing what it holds, e.g. stretching
+    /// `max_interval` during a long outage. The returned batches backing off on their own under
+    /// `BackoffScope::Batch` are retuned too, a `shared_backoff` is changed for every buffer
+    /// sharing it.
+    pub fn set_backoff(&self, backoff: ExponentialBackoff) {
+        let mut s = self.state.lock().unwrap();
+        for delayed in &mut s.delayed {
+            if let Some(running) = &mut delayed.backoff {
+                backoff.retune(running);
+            }
+        }
+        match &s.backoff {
+            Some(shared) => shared.reconfigure(&backoff),
+            None => {
+                let shared = SharedBackoff::new(&backoff);
+                shared.subscribe(s.wake_tx.clone());
+                s.backoff = Some(shared);
+            }
+        }
+        s.opts.backoff = Some(backoff);
+    }
+
+    /// Stops taking in items, what's buffered is still released with `Reason::Term` before `next`
+    /// returns `Terminal::ConsumerTerminated`
+    pub fn terminate(&self) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_err(Terminal::ConsumerTerminated)
+    }
+
+    /// A buffer driven by hand with `push` and `poll_release` from a loop of one's own, without
+    /// a source or producers. It keeps going until `terminate` is called.
+    pub fn manual(opts: RelaBufConfig<T>) -> Self {
+        Self::with_producer(opts).0
+    }
+
+    /// Takes an item in right away, gives it back while the buffer is full.
+    ///
+    /// The `intake_limiter` isn't awaited and items for a full `rejected` or `late` channel are
+    /// dropped, as nothing waits here.
+    pub fn push(&self, item: T) -> std::result::Result<(), T> {
+        let mut s = self.state.lock().unwrap();
+        if !s.can_receive() {
+            return Err(item);
+        }
+        if let Some(diverted) = s.intake(item) {
+            diverted.try_send();
+        }
+        Ok(())
+    }
+
+    /// Releases whatever is due without waiting, `None` when nothing is
+    pub fn poll_release(&self) -> Option<Result<Released<T>, Terminal>> {
+        if let Some(released) = self.pending.lock().unwrap().pop_front() {
+            return Some(Ok(released));
+        }
+
+        let reason = {
+            let mut s = self.state.lock().unwrap();
+            s.shrink();
+            s.is_ready(false)?
+        };
+        Some(release(&self.state, &self.pending, reason))
+    }
+}
+
+impl<T: Clone> RelaBuf<T> {
+    /// Copies of every item waiting to be released, urgent ones first, then the queued ones in
+    /// release order, the windows and the returned batches backing off, so debug endpoints and
+    /// tests can see exactly what is pending. Released but unresolved items are not included.
+    pub fn snapshot_items(&self) -> Vec<T> {
+        let s = self.state.lock().unwrap();
+        let panes = s.panes.iter().flat_map(Panes::pending);
+        let delayed = s.delayed.iter().flat_map(|delayed| delayed.items.iter());
+        s.urgent
+            .iter()
+            .chain(s.iter())
+            .chain(panes)
+            .chain(delayed)
+            .cloned()
+            .collect()
+    }
+}
+
+/// `RelaBuf` for a single thread, neither the items nor its futures have to be `Send`(e.g. items
+/// holding an `Rc`), so it runs on a local executor like a `LocalSet` or a GUI event loop.
+///
+/// It's fed through `RelaBufProducer` or `push`, a `RelaBufProxy` source needs `Send` items.
+pub struct LocalRelaBuf<T>(RelaBuf<T>);
+
+impl<T: 'static> LocalRelaBuf<T> {
+    pub fn with_producer(opts: RelaBufConfig<T>) -> (Self, RelaBufProducer<T>) {
+        let (buf, producer) = RelaBuf::with_producer(opts);
+        (Self(buf), producer)
+    }
+
+    pub fn manual(opts: RelaBufConfig<T>) -> Self {
+        Self(RelaBuf::manual(opts))
+    }
+
+    pub fn next(&self) -> LocalPinnedFut<'static, Result<Released<T>, Terminal>> {
+        Box::pin(self.0.release_when_ready())
+    }
+
+    pub fn ready(&self) -> LocalPinnedFut<'static, Reason> {
+        Box::pin(self.0.when_ready())
+    }
+
+    pub fn push(&self, item: T) -> std::result::Result<(), T> {
+        self.0.push(item)
+    }
+
+    pub fn poll_release(&self) -> Option<Result<Released<T>, Terminal>> {
+        self.0.poll_release()
+    }
+
+    pub fn set_backoff(&self, backoff: ExponentialBackoff) {
+        self.0.set_backoff(backoff)
+    }
+
+    pub fn subscribe_events(&self, capacity: usize) -> Receiver<ReleaseEvent> {
+        self.0.subscribe_events(capacity)
+    }
+
+    pub fn terminate(&self) {
+        self.0.terminate()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.0.stats()
+    }
+
+    pub fn errors(&self) -> Vec<RecordedError> {
+        self.0.errors()
+    }
+}
+
+/// Takes out what `reason` releases, splitting it under `max_payload`
+fn release<T>(
+    state: &Arc<Mutex<State<T>>>,
+    pending: &Mutex<VecDeque<Released<T>>>,
+    reason: Reason,
+) -> Result<Released<T>, Terminal> {
+    let mut s = state.lock().unwrap();
+    let mut consumed = s.consume(reason);
+    if reason == Reason::Term && consumed.items.is_empty() {
+        return Err(s.err.clone().unwrap());
+    }
+    let flushed = consumed.items.len() - consumed.carried;
+    s.validate_batch(&mut consumed.items, consumed.carried);
+    s.count_deadlines(&consumed.items[consumed.carried..]);
+    s.in_flight += consumed.items.len() - consumed.carried;
+    s.each_item(&consumed.items[consumed.carried..], |hooks, item| {
+        hooks.on_release(item)
+    });
+    s.track_keys(&consumed.items[consumed.carried..], false);
+    s.flush_left = s.flush_left.saturating_sub(flushed);
+    let (cuts, spans) = s.release_cuts(&mut consumed.items, consumed.window.is_some());
+    let priority = s.max_priority(&consumed.items);
+    s.observed.released(consumed.items.len());
+    let released = Released {
+        id: s.next_id(),
+        reason: if consumed.late { Reason::Late } else { reason },
+        elapsed: consumed.elapsed,
+        items: consumed.items,
+        window: consumed.window,
+        term_cause: s.err.clone().filter(|_| reason == Reason::Term),
+        priority,
+        state: Arc::clone(state),
+        backoff: consumed.backoff,
+        attempt: consumed.attempt,
+        carried: consumed.carried,
+        first_seen: consumed.first_seen.unwrap_or_else(SystemTime::now),
+        released_at: Instant::now(),
+        resolved: AtomicBool::new(false),
+    };
+    drop(s);
+
+    let mut pieces: VecDeque<_> = released.split(cuts).into();
+    for (piece, span) in pieces.iter_mut().zip(spans) {
+        piece.window = Some(span);
+    }
+    {
+        let mut s = state.lock().unwrap();
+        if !s.observers.is_empty() {
+            for piece in &pieces {
+                s.emit(piece.event(EventKind::Released));
+            }
+        }
+    }
+    if pieces.len() == 1 {
+        return Ok(pieces.pop_front().unwrap());
+    }
+    let first = pieces.pop_front().unwrap();
+    pending.lock().unwrap().extend(pieces);
+    Ok(first)
 }
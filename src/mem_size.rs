@@ -0,0 +1,115 @@
+use std::{collections::VecDeque, mem::size_of, rc::Rc, sync::Arc};
+
+/// Rough number of bytes an item holds: its inline size plus whatever heap it owns
+pub trait MemSize {
+    fn mem_size(&self) -> usize;
+}
+
+macro_rules! inline_mem_size {
+    ($($t:ty),*) => {
+        $(impl MemSize for $t {
+            fn mem_size(&self) -> usize {
+                size_of::<Self>()
+            }
+        })*
+    };
+}
+
+inline_mem_size!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+
+impl MemSize for String {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>() + self.capacity()
+    }
+}
+
+impl MemSize for &str {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>()
+    }
+}
+
+impl<T: MemSize> MemSize for Vec<T> {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>()
+            + (self.capacity() - self.len()) * size_of::<T>()
+            + self.iter().map(MemSize::mem_size).sum::<usize>()
+    }
+}
+
+impl<T: MemSize> MemSize for VecDeque<T> {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>()
+            + (self.capacity() - self.len()) * size_of::<T>()
+            + self.iter().map(MemSize::mem_size).sum::<usize>()
+    }
+}
+
+impl<T: MemSize, const N: usize> MemSize for [T; N] {
+    fn mem_size(&self) -> usize {
+        self.iter().map(MemSize::mem_size).sum()
+    }
+}
+
+impl<T: MemSize> MemSize for Box<T> {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>() + (**self).mem_size()
+    }
+}
+
+impl<T: MemSize> MemSize for Option<T> {
+    fn mem_size(&self) -> usize {
+        match self {
+            Some(item) => size_of::<Self>() - size_of::<T>() + item.mem_size(),
+            None => size_of::<Self>(),
+        }
+    }
+}
+
+/// Shared pointees are not owned by the item, only the pointer is counted
+impl<T> MemSize for Arc<T> {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>()
+    }
+}
+
+impl<T> MemSize for Rc<T> {
+    fn mem_size(&self) -> usize {
+        size_of::<Self>()
+    }
+}
+
+macro_rules! tuple_mem_size {
+    ($($name:ident),+) => {
+        impl<$($name: MemSize),+> MemSize for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn mem_size(&self) -> usize {
+                let ($($name,)+) = self;
+                size_of::<Self>() $(+ $name.mem_size() - size_of::<$name>())+
+            }
+        }
+    };
+}
+
+tuple_mem_size!(A);
+tuple_mem_size!(A, B);
+tuple_mem_size!(A, B, C);
+tuple_mem_size!(A, B, C, D);
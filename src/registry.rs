@@ -0,0 +1,95 @@
+use crate::{PinnedFut, ShutdownReport, Stats};
+use futures_lite::future;
+use std::{
+    sync::{Arc, Mutex, OnceLock},
+    task::Poll,
+    time::Duration,
+};
+
+/// What a `Registry` can do with a buffer, `None` once it's gone
+pub(crate) trait Registered: Send {
+    fn stats(&self) -> Option<Stats>;
+
+    fn flush(&self) -> Option<PinnedFut<'static>>;
+
+    fn close(&self, timeout: Duration) -> Option<PinnedFut<'static, ShutdownReport>>;
+}
+
+/// Live buffers of an application, e.g. for an admin endpoint listing them all with their stats.
+///
+/// Buffers are added with `RelaBuf::register` and drop out once they are gone.
+#[derive(Clone, Default)]
+pub struct Registry(Arc<Mutex<Vec<Box<dyn Registered>>>>);
+
+impl Registry {
+    /// Registry shared by the whole process
+    pub fn global() -> &'static Registry {
+        static GLOBAL: OnceLock<Registry> = OnceLock::new();
+        GLOBAL.get_or_init(Registry::default)
+    }
+
+    pub(crate) fn add(&self, buffer: Box<dyn Registered>) {
+        self.0.lock().unwrap().push(buffer)
+    }
+
+    /// Stats of every live buffer in the order they were registered, `Stats::name` tells them
+    /// apart
+    pub fn stats(&self) -> Vec<Stats> {
+        self.each(|buffer| buffer.stats())
+    }
+
+    /// Stats of every live buffer added up, how backed up the whole service is
+    pub fn aggregate_stats(&self) -> Stats {
+        self.stats().into_iter().sum()
+    }
+
+    /// Flushes every live buffer at once, see `RelaBuf::flush`
+    pub async fn flush_all(&self) {
+        let flushes = self.each(|buffer| buffer.flush());
+        join_all(flushes).await;
+    }
+
+    /// Closes every live buffer at once within `timeout`, see `RelaBuf::close_with_timeout`, and
+    /// adds up their reports
+    pub async fn close_all_with_timeout(&self, timeout: Duration) -> ShutdownReport {
+        let closes = self.each(|buffer| buffer.close(timeout));
+        join_all(closes).await.into_iter().sum()
+    }
+
+    fn each<R>(&self, f: impl Fn(&dyn Registered) -> Option<R>) -> Vec<R> {
+        let mut live = vec![];
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|buffer| match f(buffer.as_ref()) {
+                Some(r) => {
+                    live.push(r);
+                    true
+                }
+                None => false,
+            });
+        live
+    }
+}
+
+async fn join_all<T>(mut futs: Vec<PinnedFut<'static, T>>) -> Vec<T> {
+    let mut done: Vec<Option<T>> = futs.iter().map(|_| None).collect();
+    future::poll_fn(|cx| {
+        let mut pending = false;
+        for (fut, done) in futs.iter_mut().zip(&mut done) {
+            if done.is_none() {
+                match fut.as_mut().poll(cx) {
+                    Poll::Ready(r) => *done = Some(r),
+                    Poll::Pending => pending = true,
+                }
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+    .await;
+    done.into_iter().flatten().collect()
+}
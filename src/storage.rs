@@ -0,0 +1,370 @@
+use crate::mem_size::MemSize;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, VecDeque},
+};
+
+/// Where buffered items live between intake and release
+pub trait BatchStorage<T> {
+    fn push(&mut self, item: T);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Weight of an item, what the weight caps and `max_payload` go by without a `weigher`,
+    /// one unless the backend knows better
+    fn weigh(&self, _item: &T) -> usize {
+        1
+    }
+
+    /// Total weight of the stored items
+    fn weight(&self) -> usize {
+        self.iter().map(|item| self.weigh(item)).sum()
+    }
+
+    /// Removes up to `n` items in the order they should be released
+    fn drain_up_to(&mut self, n: usize) -> Vec<T>;
+
+    /// Puts returned items back so they are released before anything else
+    fn requeue_front(&mut self, items: Vec<T>);
+
+    /// Items in the order they would be released
+    fn iter(&self) -> Box<dyn Iterator<Item = &T> + '_>;
+
+    fn last(&self) -> Option<&T> {
+        self.iter().last()
+    }
+
+    /// How many items fit without growing, `len` unless the backend preallocates
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    /// Gives back memory kept for more than `capacity` items
+    fn shrink_to(&mut self, _capacity: usize) {}
+}
+
+impl<T> BatchStorage<T> for Vec<T> {
+    fn push(&mut self, item: T) {
+        Vec::push(self, item)
+    }
+
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn drain_up_to(&mut self, n: usize) -> Vec<T> {
+        let n = n.min(self.len());
+        self.drain(..n).collect()
+    }
+
+    fn requeue_front(&mut self, items: Vec<T>) {
+        self.splice(..0, items);
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        Box::new(self.as_slice().iter())
+    }
+
+    fn last(&self) -> Option<&T> {
+        self.as_slice().last()
+    }
+
+    fn capacity(&self) -> usize {
+        Vec::capacity(self)
+    }
+
+    fn shrink_to(&mut self, capacity: usize) {
+        Vec::shrink_to(self, capacity)
+    }
+}
+
+impl<T> BatchStorage<T> for VecDeque<T> {
+    fn push(&mut self, item: T) {
+        self.push_back(item)
+    }
+
+    fn len(&self) -> usize {
+        VecDeque::len(self)
+    }
+
+    fn drain_up_to(&mut self, n: usize) -> Vec<T> {
+        let n = n.min(self.len());
+        self.drain(..n).collect()
+    }
+
+    fn requeue_front(&mut self, items: Vec<T>) {
+        for item in items.into_iter().rev() {
+            self.push_front(item);
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        Box::new(VecDeque::iter(self))
+    }
+
+    fn last(&self) -> Option<&T> {
+        self.back()
+    }
+
+    fn capacity(&self) -> usize {
+        VecDeque::capacity(self)
+    }
+
+    fn shrink_to(&mut self, capacity: usize) {
+        VecDeque::shrink_to(self, capacity)
+    }
+}
+
+/// `Vec` backed storage weighing items by their `MemSize`
+pub struct MemSized<T> {
+    items: Vec<T>,
+    weight: usize,
+}
+
+impl<T> Default for MemSized<T> {
+    fn default() -> Self {
+        Self {
+            items: vec![],
+            weight: 0,
+        }
+    }
+}
+
+impl<T: MemSize> BatchStorage<T> for MemSized<T> {
+    fn push(&mut self, item: T) {
+        self.weight += item.mem_size();
+        self.items.push(item)
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn weigh(&self, item: &T) -> usize {
+        item.mem_size()
+    }
+
+    fn weight(&self) -> usize {
+        self.weight
+    }
+
+    fn drain_up_to(&mut self, n: usize) -> Vec<T> {
+        let drained = self.items.drain_up_to(n);
+        self.weight -= drained.iter().map(MemSize::mem_size).sum::<usize>();
+        drained
+    }
+
+    fn requeue_front(&mut self, items: Vec<T>) {
+        self.weight += items.iter().map(MemSize::mem_size).sum::<usize>();
+        self.items.requeue_front(items)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        BatchStorage::iter(&self.items)
+    }
+
+    fn last(&self) -> Option<&T> {
+        self.items.last()
+    }
+
+    fn capacity(&self) -> usize {
+        self.items.capacity()
+    }
+
+    fn shrink_to(&mut self, capacity: usize) {
+        self.items.shrink_to(capacity)
+    }
+}
+
+/// Storage coalescing runs of consecutive equal items into `(item, count)` pairs, for sources
+/// repeating themselves(status pings, unchanged metrics). Items are expanded back on release.
+pub struct RunLength<T> {
+    runs: VecDeque<(T, usize)>,
+    len: usize,
+}
+
+impl<T> Default for RunLength<T> {
+    fn default() -> Self {
+        Self {
+            runs: VecDeque::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> RunLength<T> {
+    /// Buffered runs in the order they would be released
+    pub fn runs(&self) -> impl Iterator<Item = (&T, usize)> {
+        self.runs.iter().map(|(item, count)| (item, *count))
+    }
+}
+
+impl<T: Clone + Eq> BatchStorage<T> for RunLength<T> {
+    fn push(&mut self, item: T) {
+        self.len += 1;
+        match self.runs.back_mut() {
+            Some((last, count)) if *last == item => *count += 1,
+            _ => self.runs.push_back((item, 1)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn drain_up_to(&mut self, n: usize) -> Vec<T> {
+        let n = n.min(self.len);
+        let mut drained = Vec::with_capacity(n);
+        while drained.len() < n {
+            let (item, count) = self.runs.front_mut().unwrap();
+            let take = (*count).min(n - drained.len());
+            *count -= take;
+            if *count == 0 {
+                let (item, _) = self.runs.pop_front().unwrap();
+                drained.extend(std::iter::repeat(item.clone()).take(take - 1));
+                drained.push(item);
+            } else {
+                drained.extend(std::iter::repeat(item.clone()).take(take));
+            }
+        }
+        self.len -= n;
+        drained
+    }
+
+    fn requeue_front(&mut self, items: Vec<T>) {
+        self.len += items.len();
+        for item in items.into_iter().rev() {
+            match self.runs.front_mut() {
+                Some((first, count)) if *first == item => *count += 1,
+                _ => self.runs.push_front((item, 1)),
+            }
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        Box::new(
+            self.runs
+                .iter()
+                .flat_map(|(item, count)| std::iter::repeat(item).take(*count)),
+        )
+    }
+
+    fn last(&self) -> Option<&T> {
+        self.runs.back().map(|(item, _)| item)
+    }
+}
+
+/// Storage releasing the highest priority items first instead of the oldest ones, for work
+/// queue style consumers. Equal priorities go out in arrival order, requeued items before
+/// everything else.
+///
+/// As the release order isn't the arrival order it doesn't go along with `group_by` or
+/// `max_age`.
+pub struct Heap<T, P = u64> {
+    heap: BinaryHeap<Prioritized<T, P>>,
+    requeued: VecDeque<T>,
+    priority: Box<dyn Fn(&T) -> P + Send>,
+    pushed: u64,
+}
+
+struct Prioritized<T, P> {
+    priority: P,
+    seq: u64,
+    item: T,
+}
+
+impl<T, P: Ord> Prioritized<T, P> {
+    fn key(&self) -> (&P, Reverse<u64>) {
+        (&self.priority, Reverse(self.seq))
+    }
+}
+
+impl<T, P: Ord> PartialEq for Prioritized<T, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<T, P: Ord> Eq for Prioritized<T, P> {}
+
+impl<T, P: Ord> PartialOrd for Prioritized<T, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, P: Ord> Ord for Prioritized<T, P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl<T, P> Heap<T, P> {
+    /// Items with the greatest `priority` are released first
+    pub fn by_key(priority: impl Fn(&T) -> P + Send + 'static) -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            requeued: VecDeque::new(),
+            priority: Box::new(priority),
+            pushed: 0,
+        }
+    }
+}
+
+impl<T: 'static + Clone + Ord> Heap<T, T> {
+    /// The greatest items are released first
+    pub fn ordered() -> Self {
+        Self::by_key(T::clone)
+    }
+}
+
+impl<T, P: Ord> BatchStorage<T> for Heap<T, P> {
+    fn push(&mut self, item: T) {
+        self.pushed += 1;
+        self.heap.push(Prioritized {
+            priority: (self.priority)(&item),
+            seq: self.pushed,
+            item,
+        });
+    }
+
+    fn len(&self) -> usize {
+        self.requeued.len() + self.heap.len()
+    }
+
+    fn drain_up_to(&mut self, n: usize) -> Vec<T> {
+        let requeued = n.min(self.requeued.len());
+        let mut drained: Vec<T> = self.requeued.drain(..requeued).collect();
+        while drained.len() < n {
+            match self.heap.pop() {
+                Some(prioritized) => drained.push(prioritized.item),
+                None => break,
+            }
+        }
+        drained
+    }
+
+    fn requeue_front(&mut self, items: Vec<T>) {
+        self.requeued.requeue_front(items)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &T> + '_> {
+        let mut queued: Vec<_> = self.heap.iter().collect();
+        queued.sort_unstable_by(|a, b| b.cmp(a));
+        let queued = queued.into_iter().map(|prioritized| &prioritized.item);
+        Box::new(self.requeued.iter().chain(queued))
+    }
+
+    fn capacity(&self) -> usize {
+        self.requeued.capacity() + self.heap.capacity()
+    }
+
+    fn shrink_to(&mut self, capacity: usize) {
+        self.requeued.shrink_to(0);
+        self.heap.shrink_to(capacity)
+    }
+}
@@ -0,0 +1,150 @@
+//! Chaos and soak testing helpers, for trying a config against realistic consumer failures
+//! before it goes to production. Needs the `test-util` feature.
+
+use crate::{RelaBuf, RelaBufConfig, Released, Result};
+use anyhow::anyhow;
+use async_io::Timer;
+use futures_lite::future;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+/// Consumer simulating a flaky downstream: it takes a random time within `latency` per
+/// release, fails some of them and sometimes hangs before giving up
+#[derive(Debug, Clone)]
+pub struct FlakyConsumer {
+    /// Fraction of releases returned with an error(0.0..=1.0)
+    pub failure_rate: f64,
+    /// Time a release takes, picked uniformly from the range
+    pub latency: Range<Duration>,
+    /// Fraction of releases hanging for `hang` before being returned as timed out
+    pub hang_probability: f64,
+    pub hang: Duration,
+}
+
+impl Default for FlakyConsumer {
+    fn default() -> Self {
+        Self {
+            failure_rate: 0.1,
+            latency: Duration::from_millis(1)..Duration::from_millis(10),
+            hang_probability: 0.01,
+            hang: Duration::from_secs(1),
+        }
+    }
+}
+
+impl FlakyConsumer {
+    /// Waits out the simulated write, then confirms the release or returns it with an error,
+    /// `true` when it was confirmed
+    pub async fn consume<T>(&self, released: Released<T>) -> bool {
+        if fastrand::f64() < self.hang_probability {
+            Timer::after(self.hang).await;
+            released.return_with_err(anyhow!("consumer hung"));
+            return false;
+        }
+
+        Timer::after(self.latency()).await;
+        if fastrand::f64() < self.failure_rate {
+            released.return_with_err(anyhow!("consumer failed"));
+            return false;
+        }
+        released.confirm();
+        true
+    }
+
+    fn latency(&self) -> Duration {
+        let Range { start, end } = self.latency;
+        if end <= start {
+            return start;
+        }
+        start + (end - start).mul_f64(fastrand::f64())
+    }
+}
+
+/// Outcome of `soak`
+#[derive(Debug, Clone, Default)]
+pub struct SoakReport {
+    pub sent: u64,
+    pub releases: usize,
+    /// Releases the consumer returned
+    pub failed: usize,
+    /// Items never confirmed, dropped or dead-lettered by the config for instance
+    pub lost: Vec<u64>,
+    /// Items confirmed more than once
+    pub duplicated: Vec<u64>,
+    pub elapsed: Duration,
+}
+
+impl SoakReport {
+    /// Fails unless every item was confirmed exactly once
+    pub fn check(&self) -> Result<()> {
+        if !self.lost.is_empty() || !self.duplicated.is_empty() {
+            return Err(anyhow!(
+                "{} items lost, {} duplicated out of {}",
+                self.lost.len(),
+                self.duplicated.len(),
+                self.sent
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Sends `items` numbered items through a buffer configured with `opts` as fast as it takes
+/// them in, has `consumer` take every release and reports the items that weren't confirmed
+/// exactly once. Configs copying items along(`carry`) report the copies as duplicates.
+pub async fn soak(opts: RelaBufConfig<u64>, items: u64, consumer: &FlakyConsumer) -> SoakReport {
+    let started = Instant::now();
+    let (buf, producer) = RelaBuf::with_producer(opts);
+
+    let produce = async move {
+        let mut sent = 0;
+        while sent < items {
+            match producer.send(sent).await {
+                Ok(()) => sent += 1,
+                // `strict_hard_cap` sheds load, wait for room instead
+                Err(err) if err.is::<crate::BufferFull>() => {
+                    Timer::after(Duration::from_millis(1)).await;
+                }
+                Err(_) => break,
+            }
+        }
+        sent
+    };
+
+    let consume = async {
+        let mut confirmed: HashMap<u64, usize> = HashMap::new();
+        let (mut releases, mut failed) = (0, 0);
+        while let Ok(released) = buf.next().await {
+            releases += 1;
+            let ids = released.items.clone();
+            if consumer.consume(released).await {
+                for id in ids {
+                    *confirmed.entry(id).or_default() += 1;
+                }
+            } else {
+                failed += 1;
+            }
+        }
+        (confirmed, releases, failed)
+    };
+
+    let (sent, (confirmed, releases, failed)) = future::zip(produce, consume).await;
+    let mut report = SoakReport {
+        sent,
+        releases,
+        failed,
+        elapsed: started.elapsed(),
+        ..SoakReport::default()
+    };
+    for id in 0..sent {
+        match confirmed.get(&id) {
+            None => report.lost.push(id),
+            Some(&times) if times > 1 => report.duplicated.push(id),
+            Some(_) => {}
+        }
+    }
+    report
+}
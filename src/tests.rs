@@ -0,0 +1,1140 @@
+use crate::{
+    combinators::Release, fanout::Confirmation, registry::Registry, storage::MemSized,
+    BackoffScope, Buffer, Checkpoint, Dedup, Delayed, Discarded, EventKind, EventTime,
+    ExponentialBackoff, FailureAction, Reason, RelaBuf, RelaBufConfig, SharedBackoff, State,
+    Terminal, TimeBuckets, Windowing,
+};
+use async_io::Timer;
+use futures_lite::{future, Future};
+use smol_timeout::TimeoutExt;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Runs a test body, failing it instead of hanging once it takes seconds
+fn run<F: Future>(test: F) -> F::Output {
+    future::block_on(test.timeout(Duration::from_secs(5))).expect("test timed out")
+}
+
+/// Config releasing on nothing but what a test triggers
+fn opts<T>() -> RelaBufConfig<T> {
+    RelaBufConfig {
+        soft_cap: 1000,
+        hard_cap: 2000,
+        release_after: Duration::from_secs(60),
+        backoff: None,
+        ..RelaBufConfig::default()
+    }
+}
+
+/// Backoff pausing for far longer than a test waits on a wake-up
+fn slow_backoff() -> ExponentialBackoff {
+    ExponentialBackoff {
+        initial_interval: Duration::from_secs(3),
+        randomization_factor: 0.0,
+        ..ExponentialBackoff::default()
+    }
+}
+
+#[test]
+fn next_fires_on_the_earliest_timer() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        release_after: Duration::from_millis(100),
+        poll_interval: Duration::from_secs(3),
+        ..opts()
+    });
+    run(async {
+        producer.send(1).await.unwrap();
+        let started = Instant::now();
+        let released = buf.next().await.unwrap();
+        assert_eq!(released.reason, Reason::Time);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        released.confirm();
+    });
+}
+
+/// State with an item whose `release_after` and `max_age` are long overdue
+fn overdue(opts: RelaBufConfig<u64>) -> State<u64> {
+    let mut state = State::new(
+        RelaBufConfig {
+            release_after: Duration::ZERO,
+            max_age: Some(Duration::ZERO),
+            ..opts
+        },
+        Buffer::Vec(vec![]),
+    );
+    state.add_item(1).unwrap();
+    state
+}
+
+#[test]
+fn no_timer_while_in_flight_room_is_used_up() {
+    let mut state = overdue(RelaBufConfig {
+        max_in_flight: Some(1),
+        ..opts()
+    });
+    state.in_flight = 1;
+    assert_eq!(state.is_ready(false), None);
+    assert_eq!(state.next_timer(), None);
+    state.in_flight = 0;
+    assert_eq!(state.next_timer(), Some(Duration::ZERO));
+}
+
+#[test]
+fn no_timer_while_acquired() {
+    let mut state = overdue(opts());
+    state.acquired = Some(1);
+    assert_eq!(state.is_ready(false), None);
+    assert_eq!(state.next_timer(), None);
+}
+
+#[test]
+fn ordered_retries_wait_on_the_oldest_batch() {
+    let mut state = overdue(RelaBufConfig {
+        ordered_retries: true,
+        ..opts()
+    });
+    state.delayed.push(Delayed {
+        items: vec![0],
+        backoff: None,
+        attempt: 1,
+        window: None,
+        retry_at: Some(Instant::now() + Duration::from_secs(30)),
+        first_seen: SystemTime::now(),
+    });
+    assert_eq!(state.is_ready(false), None);
+    let next = state.next_timer().unwrap();
+    assert!(next > Duration::from_secs(29));
+}
+
+#[test]
+fn held_release_is_woken_by_a_resolve() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 1,
+        max_in_flight: Some(1),
+        release_after: Duration::from_millis(1),
+        poll_interval: Duration::from_secs(3),
+        ..opts()
+    });
+    run(async {
+        producer.send(1).await.unwrap();
+        let held = buf.next().await.unwrap();
+        producer.send(2).await.unwrap();
+        let started = Instant::now();
+        let (released, _) = future::zip(buf.next(), async {
+            Timer::after(Duration::from_millis(50)).await;
+            held.confirm();
+        })
+        .await;
+        assert_eq!(released.unwrap().items, vec![2]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    });
+}
+
+#[test]
+fn full_intake_wakes_a_backed_off_buffer() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        hard_cap: 4,
+        backoff: Some(slow_backoff()),
+        bypass_backoff_when_full: true,
+        ..opts()
+    });
+    run(async {
+        producer.send(0).await.unwrap();
+        producer.send(1).await.unwrap();
+        buf.next().await.unwrap().return_on_err();
+        let started = Instant::now();
+        // the producer ends up blocked on the full intake, it's up to the release to end the race
+        let released = future::or(
+            async {
+                for i in 2.. {
+                    // paced so `next` is already waiting by the time the intake fills up
+                    Timer::after(Duration::from_millis(5)).await;
+                    producer.send(i).await.unwrap();
+                }
+                None
+            },
+            async { Some(buf.next().await) },
+        )
+        .await;
+        let released = released.unwrap().unwrap();
+        assert_eq!(released.reason, Reason::Backpressure);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        released.confirm();
+    });
+}
+
+#[test]
+fn shared_backoff_reset_wakes_every_buffer() {
+    let shared = SharedBackoff::new(&slow_backoff());
+    let config = || RelaBufConfig {
+        soft_cap: 1,
+        shared_backoff: Some(shared.clone()),
+        ..opts()
+    };
+    let (a, a_producer) = RelaBuf::with_producer(config());
+    let (b, b_producer) = RelaBuf::with_producer(config());
+    run(async {
+        b_producer.send(1).await.unwrap();
+        let held = b.next().await.unwrap();
+        a_producer.send(2).await.unwrap();
+        a.next().await.unwrap().return_on_err();
+        let started = Instant::now();
+        let (retried, _) = future::zip(a.next(), async {
+            Timer::after(Duration::from_millis(50)).await;
+            held.confirm();
+        })
+        .await;
+        let retried = retried.unwrap();
+        assert_eq!(retried.items, vec![2]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        retried.confirm();
+    });
+}
+
+#[test]
+fn weight_caps_fall_back_to_storage_weight() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap_weight: Some(100),
+        storage: Some(Box::new(MemSized::<String>::default())),
+        ..opts()
+    });
+    run(async {
+        producer.send("x".repeat(500)).await.unwrap();
+        let released = buf.next().await.unwrap();
+        assert_eq!(released.reason, Reason::Weight);
+        released.confirm();
+    });
+}
+
+#[test]
+fn hard_cap_weight_counts_window_panes() {
+    let mut state = State::new(
+        RelaBufConfig {
+            window: Some(Windowing::Tumbling(Duration::from_secs(60))),
+            hard_cap_weight: Some(2),
+            ..opts::<u64>()
+        },
+        Buffer::Vec(vec![]),
+    );
+    state.add_item(1).unwrap();
+    state.add_item(2).unwrap();
+    assert!(!state.can_receive());
+    assert_eq!(state.consume(Reason::Window).items, vec![1, 2]);
+    assert!(state.can_receive());
+}
+
+#[test]
+fn retry_without_due_batch_falls_through() {
+    let mut state = State::new(opts::<u64>(), Buffer::Vec(vec![]));
+    let consumed = state.consume(Reason::Retry);
+    assert!(consumed.items.is_empty());
+}
+
+#[test]
+fn terminal_stays_latched() {
+    let (buf, producer) = RelaBuf::with_producer(opts::<u64>());
+    run(async {
+        buf.terminate();
+        assert!(matches!(
+            buf.next().await,
+            Err(Terminal::ConsumerTerminated)
+        ));
+        let _ = producer.send(2).await;
+        assert!(matches!(
+            buf.next().await,
+            Err(Terminal::ConsumerTerminated)
+        ));
+    });
+}
+
+#[test]
+fn dropped_acquisition_aborts() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        ..opts()
+    });
+    run(async {
+        producer.send(1).await.unwrap();
+        producer.send(2).await.unwrap();
+        drop(buf.acquire().await.unwrap());
+        let released = buf.next().await.unwrap();
+        assert_eq!(released.items, vec![1, 2]);
+        released.confirm();
+    });
+}
+
+#[test]
+fn every_release_fits_in_flight_room() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        max_in_flight: Some(2),
+        max_age: Some(Duration::from_millis(20)),
+        ..opts()
+    });
+    run(async {
+        for i in 0..5 {
+            producer.send(i).await.unwrap();
+        }
+        let released = buf.next().await.unwrap();
+        assert_eq!((released.reason, released.items.len()), (Reason::Age, 2));
+        // dropped unresolved, it no longer counts as in flight
+        drop(released);
+        let released = buf.next().await.unwrap();
+        assert_eq!(released.items, vec![2, 3]);
+        released.confirm();
+        assert_eq!(buf.next().await.unwrap().items, vec![4]);
+    });
+}
+
+#[test]
+fn dropping_the_buffer_hands_over_split_pieces() {
+    let dropped = Arc::new(Mutex::new(vec![]));
+    let on_drop = Arc::clone(&dropped);
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 4,
+        max_payload: Some(2),
+        on_drop: Some(Box::new(move |items, reason| {
+            assert_eq!(reason, Discarded::Undelivered);
+            on_drop.lock().unwrap().extend(items);
+        })),
+        ..opts()
+    });
+    run(async {
+        for i in 0..4 {
+            producer.send(i).await.unwrap();
+        }
+        buf.next().await.unwrap().confirm();
+    });
+    drop(buf);
+    assert_eq!(*dropped.lock().unwrap(), vec![2, 3]);
+}
+
+#[test]
+fn urgent_items_keep_coalesce_keys_counted() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        coalesce_by: Some(Box::new(|x: &u64| x % 10)),
+        ..opts()
+    });
+    buf.push(1).unwrap();
+    producer.send_urgent(11).unwrap();
+    let urgent = buf.poll_release().unwrap().unwrap();
+    assert_eq!(
+        (urgent.reason, urgent.items.clone()),
+        (Reason::Urgent, vec![11])
+    );
+    urgent.confirm();
+    buf.push(21).unwrap();
+    buf.terminate();
+    let released = buf.poll_release().unwrap().unwrap();
+    assert_eq!(released.items, vec![1]);
+    released.confirm();
+}
+
+#[test]
+fn zero_capacity_observers_still_get_events() {
+    let (buf, _producer) = RelaBuf::with_producer(opts::<u64>());
+    let events = buf.subscribe_events(0);
+    buf.push(1).unwrap();
+    buf.terminate();
+    buf.poll_release().unwrap().unwrap().confirm();
+    assert_eq!(events.try_recv().unwrap().kind, EventKind::Released);
+}
+
+#[test]
+fn unpolled_next_keeps_split_pieces() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 4,
+        max_payload: Some(2),
+        ..opts()
+    });
+    run(async {
+        for i in 0..4 {
+            producer.send(i).await.unwrap();
+        }
+        buf.next().await.unwrap().confirm();
+        drop(buf.next());
+        assert_eq!(buf.next().await.unwrap().items, vec![2, 3]);
+    });
+}
+
+#[test]
+fn unpolled_next_keeps_time_buckets() {
+    let hour = Duration::from_secs(3600);
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 4,
+        time_buckets: Some(TimeBuckets {
+            time: Box::new(move |x: &u64| UNIX_EPOCH + hour * (*x as u32 / 2)),
+            period: hour,
+        }),
+        ..opts()
+    });
+    run(async {
+        for i in 0..4 {
+            producer.send(i).await.unwrap();
+        }
+        buf.next().await.unwrap().confirm();
+        drop(buf.next());
+        let released = buf.next().await.unwrap();
+        assert_eq!(released.items, vec![2, 3]);
+        assert_eq!(
+            released.window,
+            Some(UNIX_EPOCH + hour..UNIX_EPOCH + hour * 2)
+        );
+    });
+}
+
+#[test]
+fn unpolled_chunk_next_keeps_its_chunk() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 4,
+        ..opts()
+    });
+    let chunked = buf.chunk(2);
+    run(async {
+        for i in 0..4 {
+            producer.send(i).await.unwrap();
+        }
+        chunked.next().await.unwrap().confirm();
+        drop(chunked.next());
+        assert_eq!(chunked.next().await.unwrap().items, vec![2, 3]);
+    });
+}
+
+#[test]
+fn merge_keeps_the_losing_release() {
+    for _ in 0..10 {
+        let (a, a_producer) = RelaBuf::with_producer(RelaBufConfig {
+            soft_cap: 4,
+            ..opts()
+        });
+        let (b, b_producer) = RelaBuf::with_producer(RelaBufConfig {
+            soft_cap: 1,
+            ..opts()
+        });
+        let merged = a.chunk(2).merge(b);
+        run(async {
+            for i in 0..4 {
+                a_producer.send(i).await.unwrap();
+            }
+            b_producer.send(100).await.unwrap();
+            let mut items = vec![];
+            for _ in 0..3 {
+                let released = merged.next().await.unwrap();
+                items.extend(released.items.iter().copied());
+                released.confirm();
+            }
+            items.sort_unstable();
+            assert_eq!(items, vec![0, 1, 2, 3, 100]);
+        });
+    }
+}
+
+#[test]
+fn urgent_items_dont_outlive_termination() {
+    let (buf, producer) = RelaBuf::with_producer(opts::<u64>());
+    producer.send_urgent(1).unwrap();
+    buf.terminate();
+    let err = producer.send_urgent(2).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(Terminal::ConsumerTerminated)
+    ));
+    let released = buf.poll_release().unwrap().unwrap();
+    assert_eq!(
+        (released.reason, released.items.clone()),
+        (Reason::Term, vec![1])
+    );
+    released.confirm();
+    assert!(matches!(
+        buf.poll_release(),
+        Some(Err(Terminal::ConsumerTerminated))
+    ));
+}
+
+#[test]
+fn dropped_buffers_leave_the_registry() {
+    let registry = Registry::default();
+    let (buf, producer) = RelaBuf::with_producer(opts::<u64>());
+    buf.register(&registry);
+    assert_eq!(registry.stats().len(), 1);
+    drop(buf);
+    assert!(registry.stats().is_empty());
+    run(registry.flush_all());
+    drop(producer);
+}
+
+/// Config reporting every checkpoint to the returned list, cursors are the items themselves
+fn checkpointed(opts: RelaBufConfig<u64>) -> (RelaBufConfig<u64>, Arc<Mutex<Vec<u64>>>) {
+    let reported = Arc::new(Mutex::new(vec![]));
+    let on_checkpoint = Arc::clone(&reported);
+    let opts = RelaBufConfig {
+        checkpoint: Some(Checkpoint {
+            cursor: Box::new(|x: &u64| *x),
+            on_checkpoint: Box::new(move |through| on_checkpoint.lock().unwrap().push(through)),
+        }),
+        ..opts
+    };
+    (opts, reported)
+}
+
+#[test]
+fn checkpoint_advances_in_order() {
+    let (opts, reported) = checkpointed(RelaBufConfig {
+        soft_cap: 2,
+        ..opts()
+    });
+    let (buf, producer) = RelaBuf::with_producer(opts);
+    run(async {
+        for i in 0..4 {
+            producer.send(i).await.unwrap();
+        }
+        let first = buf.next().await.unwrap();
+        let second = buf.next().await.unwrap();
+        second.confirm();
+        // 2 and 3 wait on 0 and 1 still in flight
+        assert!(reported.lock().unwrap().is_empty());
+        first.confirm();
+    });
+    assert_eq!(*reported.lock().unwrap(), vec![3]);
+}
+
+#[test]
+fn dropped_failures_let_the_checkpoint_advance() {
+    let (opts, reported) = checkpointed(RelaBufConfig {
+        soft_cap: 2,
+        on_failure: Some(Box::new(|_, _| FailureAction::Drop)),
+        ..opts()
+    });
+    let (buf, producer) = RelaBuf::with_producer(opts);
+    run(async {
+        for i in 0..6 {
+            producer.send(i).await.unwrap();
+        }
+        buf.next()
+            .await
+            .unwrap()
+            .return_with_err(anyhow::anyhow!("bad rows"));
+        buf.next().await.unwrap().confirm();
+        buf.next().await.unwrap().confirm();
+    });
+    assert_eq!(reported.lock().unwrap().last(), Some(&5));
+}
+
+#[test]
+fn returned_items_can_be_acquired() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        ..opts()
+    });
+    run(async {
+        producer.send(1).await.unwrap();
+        producer.send(2).await.unwrap();
+        buf.next().await.unwrap().return_on_err();
+        let acquired = buf.acquire().await.unwrap();
+        assert_eq!(
+            acquired.items().iter().copied().collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        acquired.commit();
+        assert_eq!(buf.stats().buffered, 0);
+    });
+}
+
+#[test]
+fn due_delayed_batches_can_be_acquired() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        backoff_scope: BackoffScope::Batch,
+        retry_delay: Some(Box::new(|_, _| Some(Duration::ZERO))),
+        ..opts()
+    });
+    run(async {
+        producer.send(1).await.unwrap();
+        producer.send(2).await.unwrap();
+        buf.next().await.unwrap().return_on_err();
+        producer.send(3).await.unwrap();
+        let acquired = buf.acquire().await.unwrap();
+        assert_eq!(acquired.reason, Reason::Retry);
+        assert_eq!(
+            acquired.items().iter().copied().collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        acquired.commit();
+        assert_eq!(buf.stats().confirmed, 2);
+    });
+}
+
+#[test]
+fn window_panes_cant_be_acquired() {
+    let (buf, _producer) = RelaBuf::with_producer(RelaBufConfig {
+        window: Some(Windowing::Tumbling(Duration::from_secs(60))),
+        ..opts::<u64>()
+    });
+    run(async {
+        assert!(matches!(buf.acquire().await, Err(Terminal::Failed(_))));
+    });
+}
+
+#[test]
+fn resolve_each_retries_the_failed_items_alone() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 3,
+        ..opts()
+    });
+    run(async {
+        for i in 0..3 {
+            producer.send(i).await.unwrap();
+        }
+        let released = buf.next().await.unwrap();
+        released.resolve_each(vec![Ok(()), Err(anyhow::anyhow!("bad row")), Ok(())]);
+        assert_eq!(buf.stats().confirmed, 2);
+        let retried = buf.next().await.unwrap();
+        assert_eq!(
+            (retried.reason, retried.items.clone(), retried.attempt),
+            (Reason::Retry, vec![1], 1)
+        );
+        retried.resolve_each(vec![Err(anyhow::anyhow!("bad row"))]);
+        assert_eq!(buf.next().await.unwrap().attempt, 2);
+    });
+}
+
+#[test]
+fn a_row_failing_next_to_confirmed_ones_exhausts_its_retries() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        backoff: Some(ExponentialBackoff {
+            initial_interval: Duration::from_millis(5),
+            multiplier: 1.0,
+            randomization_factor: 0.0,
+            max_elapsed_time: Some(Duration::from_millis(50)),
+            ..ExponentialBackoff::default()
+        }),
+        ..opts()
+    });
+    run(async {
+        let mut fresh = 1;
+        producer.send(0).await.unwrap();
+        producer.send(fresh).await.unwrap();
+        loop {
+            let released = match buf.next().await {
+                Ok(released) => released,
+                Err(err) => {
+                    assert!(matches!(err, Terminal::RetriesExhausted));
+                    break;
+                }
+            };
+            let results = released
+                .items
+                .iter()
+                .map(|&x| match x {
+                    0 => Err(anyhow::anyhow!("bad row")),
+                    _ => Ok(()),
+                })
+                .collect();
+            released.resolve_each(results);
+            fresh += 1;
+            let _ = producer.send(fresh).await;
+        }
+    });
+    assert_eq!(buf.stats().exhausted, 1);
+}
+
+#[test]
+fn failed_confirm_hooks_return_with_their_error() {
+    let causes = Arc::new(Mutex::new(vec![]));
+    let on_failure = Arc::clone(&causes);
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 1,
+        on_confirm: Some(Box::new(|_: &[u64]| {
+            Box::pin(async { Err(anyhow::anyhow!("sink down")) })
+        })),
+        on_failure: Some(Box::new(move |err, _| {
+            on_failure.lock().unwrap().push(err.to_string());
+            FailureAction::Retry
+        })),
+        ..opts()
+    });
+    run(async {
+        producer.send(1).await.unwrap();
+        let err = buf.next().await.unwrap().confirm_async().await.unwrap_err();
+        assert!(err.to_string().contains("sink down"));
+        assert_eq!(buf.errors().last().unwrap().err.to_string(), "sink down");
+        assert_eq!(buf.next().await.unwrap().items, vec![1]);
+    });
+    assert_eq!(*causes.lock().unwrap(), vec!["sink down".to_string()]);
+}
+
+#[test]
+fn acquisitions_emit_release_events() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        ..opts()
+    });
+    let events = buf.subscribe_events(8);
+    run(async {
+        producer.send(1).await.unwrap();
+        producer.send(2).await.unwrap();
+        buf.acquire().await.unwrap().abort();
+        buf.acquire().await.unwrap().commit();
+    });
+    let kinds: Vec<_> = events
+        .try_iter()
+        .map(|event| (event.kind, event.items))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (EventKind::Released, 2),
+            (EventKind::Returned, 2),
+            (EventKind::Released, 2),
+            (EventKind::Confirmed, 2),
+        ]
+    );
+}
+
+#[test]
+fn lanes_take_turns() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 4,
+        ..opts()
+    });
+    let (firehose, quiet) = (producer.lane(1), producer.lane(1));
+    run(async {
+        for i in 0..6 {
+            firehose.send(i).await.unwrap();
+        }
+        quiet.send(100).await.unwrap();
+        quiet.send(101).await.unwrap();
+        let released = buf.next().await.unwrap();
+        let mut items = released.items.clone();
+        items.sort_unstable();
+        assert_eq!(items, vec![0, 1, 100, 101]);
+        released.confirm();
+    });
+}
+
+#[test]
+fn new_lanes_wake_a_waiting_next() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 1,
+        poll_interval: Duration::from_secs(3),
+        ..opts()
+    });
+    run(async {
+        let started = Instant::now();
+        let (released, _) = future::zip(buf.next(), async {
+            Timer::after(Duration::from_millis(50)).await;
+            producer.lane(1).send(1).await.unwrap();
+        })
+        .await;
+        assert_eq!(released.unwrap().items, vec![1]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    });
+}
+
+#[test]
+fn full_lanes_bypass_the_backoff() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        hard_cap: 4,
+        backoff: Some(slow_backoff()),
+        bypass_backoff_when_full: true,
+        ..opts()
+    });
+    let lane = producer.lane(1);
+    run(async {
+        lane.send(0).await.unwrap();
+        lane.send(1).await.unwrap();
+        buf.next().await.unwrap().return_on_err();
+        let started = Instant::now();
+        let released = future::or(
+            async {
+                for i in 2.. {
+                    Timer::after(Duration::from_millis(5)).await;
+                    lane.send(i).await.unwrap();
+                }
+                None
+            },
+            async { Some(buf.next().await) },
+        )
+        .await;
+        let released = released.unwrap().unwrap();
+        assert_eq!(released.reason, Reason::Backpressure);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        released.confirm();
+    });
+}
+
+#[test]
+fn std_bridge_stops_once_the_buffer_is_gone() {
+    let (tx, rx) = std::sync::mpsc::channel::<u64>();
+    let buf = RelaBuf::from_std_receiver(
+        RelaBufConfig {
+            poll_interval: Duration::from_millis(10),
+            ..opts()
+        },
+        rx,
+    )
+    .unwrap();
+    drop(buf);
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(tx.send(1).is_err());
+}
+
+#[test]
+fn close_returns_once_the_last_release_resolves() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        ..opts::<u64>()
+    });
+    run(async {
+        producer.send(0).await.unwrap();
+        producer.send(1).await.unwrap();
+        let released = buf.next().await.unwrap();
+        let started = Instant::now();
+        let (report, _) = future::zip(buf.close_with_timeout(Duration::from_secs(3)), async {
+            Timer::after(Duration::from_millis(50)).await;
+            released.confirm();
+        })
+        .await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(report.delivered, 2);
+        assert_eq!(report.stranded, 0);
+    });
+}
+
+#[test]
+fn close_reports_what_is_unresolved_at_the_timeout() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        ..opts::<u64>()
+    });
+    run(async {
+        producer.send(0).await.unwrap();
+        producer.send(1).await.unwrap();
+        let _released = buf.next().await.unwrap();
+        let report = buf.close_with_timeout(Duration::from_millis(50)).await;
+        assert_eq!(report.delivered, 0);
+        assert_eq!(report.stranded, 2);
+    });
+}
+
+#[test]
+fn tumbling_windows_release_once_they_close() {
+    let period = Duration::from_millis(200);
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        window: Some(Windowing::Tumbling(period)),
+        ..opts::<u64>()
+    });
+    run(async {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let into_window = since_epoch.as_nanos() % period.as_nanos();
+        Timer::after(period - Duration::from_nanos(into_window as u64)).await;
+        let opened = SystemTime::now();
+        producer.send(0).await.unwrap();
+        producer.send(1).await.unwrap();
+        let released = buf.next().await.unwrap();
+        assert_eq!(
+            (released.reason, released.items.clone()),
+            (Reason::Window, vec![0, 1])
+        );
+        let window = released.window.clone().unwrap();
+        assert_eq!(window.end.duration_since(window.start).unwrap(), period);
+        assert!(window.contains(&opened));
+        assert!(SystemTime::now() >= window.end);
+        released.confirm();
+    });
+}
+
+/// Event time of the items, milliseconds past a fixed second
+fn at_ms(x: &u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1000) + Duration::from_millis(*x)
+}
+
+fn secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[test]
+fn sliding_windows_carry_earlier_items() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        window: Some(Windowing::Sliding {
+            size: Duration::from_secs(2),
+            slide: Duration::from_secs(1),
+        }),
+        carry: Some(|x| *x),
+        event_time: Some(EventTime {
+            time: Box::new(at_ms),
+            max_delay: Duration::ZERO,
+            allowed_lateness: Duration::ZERO,
+        }),
+        ..opts::<u64>()
+    });
+    run(async {
+        producer.send(500).await.unwrap();
+        producer.send(1500).await.unwrap();
+        let first = buf.next().await.unwrap();
+        assert_eq!(first.items, vec![500]);
+        assert_eq!(first.window, Some(secs(999)..secs(1001)));
+        first.confirm();
+
+        producer.send(2500).await.unwrap();
+        let second = buf.next().await.unwrap();
+        assert_eq!(second.items, vec![500, 1500]);
+        assert_eq!(second.window, Some(secs(1000)..secs(1002)));
+        second.confirm();
+        assert_eq!(buf.stats().confirmed, 2);
+    });
+}
+
+#[test]
+fn session_windows_close_after_the_gap_per_key() {
+    let gap = Duration::from_millis(100);
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        window: Some(Windowing::Session(gap)),
+        window_key: Some(Box::new(|x: &u64| x % 2)),
+        ..opts::<u64>()
+    });
+    run(async {
+        let started = Instant::now();
+        for i in 0..3 {
+            producer.send(i).await.unwrap();
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        let even = buf.next().await.unwrap();
+        assert!(started.elapsed() >= gap);
+        assert_eq!(
+            (even.reason, even.items.clone()),
+            (Reason::Window, vec![0, 2])
+        );
+        even.confirm();
+        let odd = buf.next().await.unwrap();
+        assert_eq!(odd.items, vec![1]);
+        odd.confirm();
+
+        producer.send(4).await.unwrap();
+        assert_eq!(buf.next().await.unwrap().items, vec![4]);
+    });
+}
+
+#[test]
+fn count_windows_overlap_by_carried_copies() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        window: Some(Windowing::Count {
+            size: 3,
+            overlap: 1,
+        }),
+        carry: Some(|x| *x),
+        ..opts::<u64>()
+    });
+    run(async {
+        for i in 0..4 {
+            producer.send(i).await.unwrap();
+        }
+        let first = buf.next().await.unwrap();
+        assert_eq!(
+            (first.reason, first.items.clone()),
+            (Reason::Window, vec![0, 1, 2])
+        );
+        first.confirm();
+
+        for i in 4..7 {
+            producer.send(i).await.unwrap();
+        }
+        let second = buf.next().await.unwrap();
+        assert_eq!(second.items, vec![2, 3, 4, 5]);
+        second.confirm();
+        assert_eq!(buf.stats().confirmed, 6);
+    });
+}
+
+#[test]
+fn event_time_windows_wait_for_the_watermark() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        window: Some(Windowing::Tumbling(Duration::from_secs(1))),
+        event_time: Some(EventTime {
+            time: Box::new(at_ms),
+            max_delay: Duration::from_millis(500),
+            allowed_lateness: Duration::ZERO,
+        }),
+        ..opts::<u64>()
+    });
+    run(async {
+        // 1300 only moves the watermark to 800, 100 still makes its window
+        for x in [200, 900, 1300, 100, 1600] {
+            producer.send(x).await.unwrap();
+        }
+        let released = buf.next().await.unwrap();
+        assert_eq!(
+            (released.reason, released.items.clone()),
+            (Reason::Window, vec![200, 900, 100])
+        );
+        assert_eq!(released.window, Some(secs(1000)..secs(1001)));
+        released.confirm();
+    });
+}
+
+#[test]
+fn late_items_are_released_within_the_lateness_and_diverted_after() {
+    let (late_tx, late_rx) = flume::unbounded();
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        window: Some(Windowing::Tumbling(Duration::from_secs(1))),
+        event_time: Some(EventTime {
+            time: Box::new(at_ms),
+            max_delay: Duration::ZERO,
+            allowed_lateness: Duration::from_secs(1),
+        }),
+        late: Some(late_tx),
+        ..opts::<u64>()
+    });
+    run(async {
+        producer.send(500).await.unwrap();
+        producer.send(1500).await.unwrap();
+        let released = buf.next().await.unwrap();
+        assert_eq!(released.items, vec![500]);
+        released.confirm();
+
+        producer.send(700).await.unwrap();
+        let late = buf.next().await.unwrap();
+        assert_eq!((late.reason, late.items.clone()), (Reason::Late, vec![700]));
+        assert_eq!(late.window, Some(secs(1000)..secs(1001)));
+        late.confirm();
+
+        producer.send(3500).await.unwrap();
+        let released = buf.next().await.unwrap();
+        assert_eq!(released.items, vec![1500]);
+        released.confirm();
+        producer.send(200).await.unwrap();
+        producer.send(4500).await.unwrap();
+        let released = buf.next().await.unwrap();
+        assert_eq!(released.items, vec![3500]);
+        released.confirm();
+        let too_late = late_rx.try_recv().unwrap();
+        assert_eq!(too_late.item, 200);
+        assert_eq!(too_late.window, secs(1000)..secs(1001));
+        assert_eq!(buf.stats().too_late, 1);
+    });
+}
+
+#[test]
+fn dedup_drops_recently_confirmed_keys() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 1,
+        dedup: Some(Dedup {
+            key: Box::new(|x: &u64| *x),
+            capacity: 2,
+            ttl: None,
+        }),
+        ..opts()
+    });
+    run(async {
+        for i in [1, 1, 2, 3, 1] {
+            producer.send(i).await.unwrap();
+        }
+        let mut released = vec![];
+        for _ in 0..4 {
+            let next = buf.next().await.unwrap();
+            released.extend(next.items.iter().copied());
+            next.confirm();
+        }
+        // 1 was forgotten once 2 and 3 were confirmed
+        assert_eq!(released, vec![1, 2, 3, 1]);
+        assert_eq!(buf.stats().deduplicated, 1);
+    });
+}
+
+#[test]
+fn broadcasts_need_every_subscriber_to_confirm() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        ..opts::<u64>()
+    });
+    run(async {
+        producer.send(0).await.unwrap();
+        producer.send(1).await.unwrap();
+        let mut shares = buf.next().await.unwrap().broadcast(3, Confirmation::All);
+        assert!(shares.iter().all(|share| share.items == vec![0, 1]));
+        shares.pop().unwrap().return_on_err();
+        for share in shares {
+            share.confirm();
+        }
+        assert_eq!(buf.stats().confirmed, 0);
+
+        let retried = buf.next().await.unwrap();
+        assert_eq!(retried.items, vec![0, 1]);
+        for share in retried.broadcast(3, Confirmation::All) {
+            share.confirm();
+        }
+        assert_eq!(buf.stats().confirmed, 2);
+    });
+}
+
+#[test]
+fn quorum_broadcasts_confirm_despite_a_failed_share() {
+    let failed = Arc::new(Mutex::new(vec![]));
+    let on_failure = Arc::clone(&failed);
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 2,
+        ..opts::<u64>()
+    });
+    run(async {
+        producer.send(0).await.unwrap();
+        producer.send(1).await.unwrap();
+        let quorum = Confirmation::Quorum {
+            k: 2,
+            on_failure: Some(Box::new(move |subscriber, _| {
+                on_failure.lock().unwrap().push(subscriber)
+            })),
+        };
+        let mut shares = buf.next().await.unwrap().broadcast(3, quorum).into_iter();
+        shares
+            .next()
+            .unwrap()
+            .return_with_err(anyhow::anyhow!("replica down"));
+        shares.next().unwrap().confirm();
+        assert_eq!(buf.stats().confirmed, 0);
+        shares.next().unwrap().confirm();
+        assert_eq!(buf.stats().confirmed, 2);
+        assert_eq!(*failed.lock().unwrap(), vec![0]);
+
+        producer.send(2).await.unwrap();
+        producer.send(3).await.unwrap();
+        let quorum = Confirmation::Quorum {
+            k: 2,
+            on_failure: None,
+        };
+        let mut shares = buf.next().await.unwrap().broadcast(3, quorum).into_iter();
+        shares.next().unwrap().return_on_err();
+        shares.next().unwrap().return_on_err();
+        shares.next().unwrap().confirm();
+        let retried = buf.next().await.unwrap();
+        assert_eq!(retried.items, vec![2, 3]);
+        retried.confirm();
+    });
+}
+
+#[test]
+fn releases_are_split_by_payload_weight() {
+    let (buf, producer) = RelaBuf::with_producer(RelaBufConfig {
+        soft_cap: 5,
+        max_payload: Some(4),
+        weigher: Some(Box::new(|x: &u64| *x as usize)),
+        ..opts()
+    });
+    run(async {
+        for i in [1, 2, 2, 5, 1] {
+            producer.send(i).await.unwrap();
+        }
+        let mut pieces = vec![];
+        while pieces.len() < 4 {
+            let piece = buf.next().await.unwrap();
+            assert_eq!(piece.reason, Reason::Size);
+            pieces.push(piece.items.clone());
+            piece.confirm();
+        }
+        // 5 is heavier than the payload on its own
+        assert_eq!(pieces, vec![vec![1, 2], vec![2], vec![5], vec![1]]);
+    });
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Cuts releases into windows, replacing the cap, time and group triggers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Windowing {
+    /// Fixed, non-overlapping windows of arrival time aligned to the wall clock, each one is
+    /// released once when it closes
+    Tumbling(Duration),
+    /// Windows of `size` starting every `slide`, each release holds every item that arrived
+    /// within its window.
+    ///
+    /// Items of earlier windows are copied into later ones with `RelaBufConfig::carry`, only the
+    /// items that arrived during the latest `slide` are confirmed or returned by the release.
+    /// Windows without new items are skipped.
+    Sliding { size: Duration, slide: Duration },
+    /// Closes a window once no item arrived for the given gap, so bursts get batched per session.
+    ///
+    /// With `RelaBufConfig::window_key` every key has sessions of its own.
+    Session(Duration),
+    /// Releases every `size` items, copies of the last `overlap` ones start the next window with
+    /// `RelaBufConfig::carry`
+    Count { size: usize, overlap: usize },
+}
+
+/// Index of the wall-clock window of `period` length `at` falls into
+pub(crate) fn wall_window(at: SystemTime, period: Duration) -> u128 {
+    let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_nanos() / period.as_nanos().max(1)
+}
+
+/// Start of the wall-clock window `index` of `period` length
+pub(crate) fn window_start(index: u128, period: Duration) -> SystemTime {
+    let nanos = index * period.as_nanos();
+    let since_epoch = Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    );
+    UNIX_EPOCH + since_epoch
+}
+
+/// A window taken out of `Panes`, the first `carried` items are copies from earlier windows
+pub(crate) struct Pane<T> {
+    pub(crate) span: Range<SystemTime>,
+    pub(crate) items: Vec<T>,
+    pub(crate) carried: usize,
+    /// Items that arrived after their event time window was released, within the lateness
+    pub(crate) late: bool,
+}
+
+struct Bucket<T> {
+    items: Vec<T>,
+    key: u64,
+    opened: SystemTime,
+    started: Instant,
+    last: Instant,
+}
+
+/// Items bucketed by the window they belong to, oldest window first
+pub(crate) struct Panes<T> {
+    windowing: Windowing,
+    panes: BTreeMap<u128, Bucket<T>>,
+    len: usize,
+    /// Open session of every key
+    sessions: HashMap<u64, u128>,
+    next_session: u128,
+    pushed: u128,
+    /// Under event time windows close once the watermark, the latest event time seen minus
+    /// this delay, passes them
+    max_delay: Option<Duration>,
+    allowed_lateness: Duration,
+    latest_event: Option<SystemTime>,
+    /// Newest event time window released so far
+    released_through: Option<u128>,
+    /// Copies of released panes still inside later sliding windows
+    carry: BTreeMap<u128, Vec<T>>,
+    dup: Option<fn(&T) -> T>,
+}
+
+impl<T> Panes<T> {
+    pub(crate) fn new(
+        windowing: Windowing,
+        dup: Option<fn(&T) -> T>,
+        event_time: Option<(Duration, Duration)>,
+    ) -> Self {
+        Self {
+            windowing,
+            panes: BTreeMap::new(),
+            len: 0,
+            sessions: HashMap::new(),
+            next_session: 0,
+            pushed: 0,
+            max_delay: event_time.map(|(max_delay, _)| max_delay),
+            allowed_lateness: event_time.map_or(Duration::ZERO, |(_, lateness)| lateness),
+            latest_event: None,
+            released_through: None,
+            carry: BTreeMap::new(),
+            dup,
+        }
+    }
+
+    /// `at` is the item's event time under event time windows, items too late even for the
+    /// allowed lateness are given back with the window they were meant for
+    pub(crate) fn push(
+        &mut self,
+        item: T,
+        key: u64,
+        at: Option<SystemTime>,
+    ) -> Result<&T, (T, Range<SystemTime>)> {
+        self.latest_event = self.latest_event.max(at);
+        let pane = match self.windowing {
+            Windowing::Session(gap) => match self.sessions.get(&key) {
+                Some(session) if !self.panes[session].is_over(gap) => *session,
+                _ => {
+                    self.next_session += 1;
+                    self.sessions.insert(key, self.next_session);
+                    self.next_session
+                }
+            },
+            _ => self.current_at(at.unwrap_or_else(SystemTime::now)),
+        };
+        if matches!(self.released_through, Some(through) if pane <= through) {
+            let span = self.span(pane).unwrap();
+            if span.end + self.allowed_lateness <= self.watermark() {
+                return Err((item, span));
+            }
+        }
+        let now = Instant::now();
+        let bucket = self.panes.entry(pane).or_insert_with(|| Bucket {
+            items: vec![],
+            key,
+            opened: SystemTime::now(),
+            started: now,
+            last: now,
+        });
+        bucket.items.push(item);
+        bucket.last = now;
+        self.len += 1;
+        self.pushed += 1;
+        Ok(bucket.items.last().unwrap())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Items of every window, carried copies included
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        let carried = self.carry.values().flatten();
+        self.pending().chain(carried)
+    }
+
+    /// Items of every window not released yet, oldest window first
+    pub(crate) fn pending(&self) -> impl Iterator<Item = &T> {
+        self.panes.values().flat_map(|bucket| bucket.items.iter())
+    }
+
+    /// Whether a window is over
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed().is_some()
+    }
+
+    fn closed(&self) -> Option<u128> {
+        match self.windowing {
+            Windowing::Session(gap) => self
+                .panes
+                .iter()
+                .find(|(_, bucket)| bucket.is_over(gap))
+                .map(|(&pane, _)| pane),
+            _ => {
+                let first = *self.panes.keys().next()?;
+                (first < self.current()).then_some(first)
+            }
+        }
+    }
+
+    /// Time until the next window closes on its own, `None` when only arrivals close one
+    pub(crate) fn closes_in(&self) -> Option<Duration> {
+        match self.windowing {
+            Windowing::Session(gap) => self
+                .panes
+                .values()
+                .map(|bucket| gap.saturating_sub(bucket.last.elapsed()))
+                .min(),
+            Windowing::Count { .. } => None,
+            // the watermark only moves as events arrive
+            _ if self.max_delay.is_some() => None,
+            _ => {
+                let first = *self.panes.keys().next()?;
+                let end = window_start(first + 1, self.period()?);
+                Some(end.duration_since(SystemTime::now()).unwrap_or_default())
+            }
+        }
+    }
+
+    /// Takes a closed window, or the oldest one when none is closed
+    pub(crate) fn pop(&mut self) -> Option<Pane<T>> {
+        let key = self
+            .closed()
+            .or_else(|| self.panes.keys().next().copied())?;
+        let bucket = self.panes.remove(&key)?;
+        let own = bucket.items;
+        self.len -= own.len();
+
+        let late = matches!(self.released_through, Some(through) if key <= through);
+        if self.max_delay.is_some() && self.period().is_some() {
+            self.released_through = self.released_through.max(Some(key));
+        }
+
+        match self.windowing {
+            Windowing::Tumbling(_) => Some(Pane {
+                span: self.span(key).unwrap(),
+                items: own,
+                carried: 0,
+                late,
+            }),
+            Windowing::Sliding { size, slide } => {
+                let slide = slide.as_nanos().max(1);
+                let panes = ((size.as_nanos() + slide - 1) / slide).max(1);
+                let first = (key + 1).saturating_sub(panes);
+                let mut items = vec![];
+                if let Some(dup) = self.dup {
+                    for (_, carried) in self.carry.range(first..key) {
+                        items.extend(carried.iter().map(dup));
+                    }
+                    self.carry.insert(key, own.iter().map(dup).collect());
+                    self.carry.retain(|&k, _| k + panes > key + 1);
+                }
+                let carried = items.len();
+                items.extend(own);
+
+                Some(Pane {
+                    span: self.span(key).unwrap(),
+                    items,
+                    carried,
+                    late,
+                })
+            }
+            Windowing::Count { overlap, .. } => {
+                // the carried tail already is a copy
+                let mut items: Vec<T> = std::mem::take(&mut self.carry)
+                    .into_values()
+                    .flatten()
+                    .collect();
+                let carried = items.len();
+                items.extend(own);
+                if let Some(dup) = self.dup {
+                    let tail = items.len().saturating_sub(overlap);
+                    self.carry
+                        .insert(key, items[tail..].iter().map(dup).collect());
+                }
+
+                let now = SystemTime::now();
+                Some(Pane {
+                    span: bucket.opened..now,
+                    items,
+                    carried,
+                    late: false,
+                })
+            }
+            Windowing::Session(gap) => {
+                if self.sessions.get(&bucket.key) == Some(&key) {
+                    self.sessions.remove(&bucket.key);
+                }
+                let end = bucket.opened + bucket.last.duration_since(bucket.started) + gap;
+                Some(Pane {
+                    span: bucket.opened..end,
+                    items: own,
+                    carried: 0,
+                    late: false,
+                })
+            }
+        }
+    }
+
+    /// What time windows are closed against, the event time watermark or the wall clock
+    fn watermark(&self) -> SystemTime {
+        match self.max_delay {
+            Some(max_delay) => self
+                .latest_event
+                .and_then(|latest| latest.checked_sub(max_delay))
+                .unwrap_or(UNIX_EPOCH),
+            None => SystemTime::now(),
+        }
+    }
+
+    fn current(&self) -> u128 {
+        self.current_at(self.watermark())
+    }
+
+    fn current_at(&self, at: SystemTime) -> u128 {
+        match self.windowing {
+            Windowing::Session(_) => self.next_session,
+            Windowing::Count { size, .. } => self.pushed / size.max(1) as u128,
+            _ => wall_window(at, self.period().unwrap()),
+        }
+    }
+
+    /// Length of the panes time windows are made of
+    fn period(&self) -> Option<Duration> {
+        match self.windowing {
+            Windowing::Tumbling(size) => Some(size),
+            Windowing::Sliding { slide, .. } => Some(slide),
+            _ => None,
+        }
+    }
+
+    /// Time window a pane is released as
+    fn span(&self, key: u128) -> Option<Range<SystemTime>> {
+        match self.windowing {
+            Windowing::Tumbling(size) => Some(window_start(key, size)..window_start(key + 1, size)),
+            Windowing::Sliding { size, slide } => {
+                let end = window_start(key + 1, slide);
+                Some(end.checked_sub(size).unwrap_or(UNIX_EPOCH)..end)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<T> Bucket<T> {
+    fn is_over(&self, gap: Duration) -> bool {
+        self.last.elapsed() >= gap
+    }
+}